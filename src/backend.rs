@@ -0,0 +1,398 @@
+use colink::{CoLink, Participant};
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+};
+use tokio::sync::Notify;
+
+type Error = Box<dyn std::error::Error + Send + Sync + 'static>;
+// (task_id, key, sender user_id, receiver user_id)
+type VariableKey = (String, String, String, String);
+
+/// The CoLink operations the interpreter needs to run a task.
+#[colink::async_trait]
+pub trait Backend: Send + Sync {
+    fn get_user_id(&self) -> Result<String, String>;
+    fn get_task_id(&self) -> Result<String, String>;
+    fn get_core_addr(&self) -> Result<String, String>;
+    fn get_jwt(&self) -> Result<String, String>;
+    async fn send_variable(
+        &self,
+        key: &str,
+        payload: &[u8],
+        receivers: &[Participant],
+    ) -> Result<(), Error>;
+    async fn recv_variable(&self, key: &str, sender: &Participant) -> Result<Vec<u8>, Error>;
+    async fn create_entry(&self, key: &str, payload: &[u8]) -> Result<(), Error>;
+    async fn update_entry(&self, key: &str, payload: &[u8]) -> Result<(), Error>;
+    async fn delete_entry(&self, key: &str) -> Result<(), Error>;
+    async fn read_entry(&self, key: &str) -> Result<Vec<u8>, Error>;
+    async fn read_or_wait(&self, key: &str) -> Result<Vec<u8>, Error>;
+}
+
+#[colink::async_trait]
+impl Backend for CoLink {
+    fn get_user_id(&self) -> Result<String, String> {
+        CoLink::get_user_id(self)
+    }
+
+    fn get_task_id(&self) -> Result<String, String> {
+        CoLink::get_task_id(self)
+    }
+
+    fn get_core_addr(&self) -> Result<String, String> {
+        CoLink::get_core_addr(self)
+    }
+
+    fn get_jwt(&self) -> Result<String, String> {
+        CoLink::get_jwt(self)
+    }
+
+    async fn send_variable(
+        &self,
+        key: &str,
+        payload: &[u8],
+        receivers: &[Participant],
+    ) -> Result<(), Error> {
+        CoLink::send_variable(self, key, payload, receivers).await
+    }
+
+    async fn recv_variable(&self, key: &str, sender: &Participant) -> Result<Vec<u8>, Error> {
+        CoLink::recv_variable(self, key, sender).await
+    }
+
+    async fn create_entry(&self, key: &str, payload: &[u8]) -> Result<(), Error> {
+        CoLink::create_entry(self, key, payload).await?;
+        Ok(())
+    }
+
+    async fn update_entry(&self, key: &str, payload: &[u8]) -> Result<(), Error> {
+        CoLink::update_entry(self, key, payload).await?;
+        Ok(())
+    }
+
+    async fn delete_entry(&self, key: &str) -> Result<(), Error> {
+        CoLink::delete_entry(self, key).await?;
+        Ok(())
+    }
+
+    async fn read_entry(&self, key: &str) -> Result<Vec<u8>, Error> {
+        CoLink::read_entry(self, key).await
+    }
+
+    async fn read_or_wait(&self, key: &str) -> Result<Vec<u8>, Error> {
+        CoLink::read_or_wait(self, key).await
+    }
+}
+
+/// Entries and variables of a CoLink server kept in memory, shared by all the
+/// `MemoryBackend`s of a local run.
+#[derive(Default)]
+pub struct MemoryStorage {
+    // (user_id, key) -> value
+    entries: Mutex<HashMap<(String, String), Vec<u8>>>,
+    variables: Mutex<HashMap<VariableKey, Vec<u8>>>,
+    // woken up on every write, so readers waiting for a key can check again
+    updated: Notify,
+}
+
+/// In-memory `Backend` for one participant, used by `local-run` and to
+/// exercise the interpreter without a server.
+#[derive(Clone)]
+pub struct MemoryBackend {
+    storage: Arc<MemoryStorage>,
+    user_id: String,
+    task_id: String,
+}
+
+impl MemoryBackend {
+    pub fn new(storage: Arc<MemoryStorage>, user_id: &str, task_id: &str) -> MemoryBackend {
+        MemoryBackend {
+            storage,
+            user_id: user_id.to_string(),
+            task_id: task_id.to_string(),
+        }
+    }
+}
+
+#[colink::async_trait]
+impl Backend for MemoryBackend {
+    fn get_user_id(&self) -> Result<String, String> {
+        Ok(self.user_id.clone())
+    }
+
+    fn get_task_id(&self) -> Result<String, String> {
+        Ok(self.task_id.clone())
+    }
+
+    fn get_core_addr(&self) -> Result<String, String> {
+        Err("core_addr not available in memory backend".to_string())
+    }
+
+    fn get_jwt(&self) -> Result<String, String> {
+        Err("jwt not available in memory backend".to_string())
+    }
+
+    async fn send_variable(
+        &self,
+        key: &str,
+        payload: &[u8],
+        receivers: &[Participant],
+    ) -> Result<(), Error> {
+        let mut variables = self.storage.variables.lock().unwrap();
+        for receiver in receivers {
+            variables.insert(
+                (
+                    self.task_id.clone(),
+                    key.to_string(),
+                    self.user_id.clone(),
+                    receiver.user_id.clone(),
+                ),
+                payload.to_vec(),
+            );
+        }
+        self.storage.updated.notify_waiters();
+        Ok(())
+    }
+
+    async fn recv_variable(&self, key: &str, sender: &Participant) -> Result<Vec<u8>, Error> {
+        let variable_key = (
+            self.task_id.clone(),
+            key.to_string(),
+            sender.user_id.clone(),
+            self.user_id.clone(),
+        );
+        loop {
+            // created before checking so that a write in between still wakes us up
+            let updated = self.storage.updated.notified();
+            if let Some(payload) = self.storage.variables.lock().unwrap().get(&variable_key) {
+                return Ok(payload.clone());
+            }
+            updated.await;
+        }
+    }
+
+    async fn create_entry(&self, key: &str, payload: &[u8]) -> Result<(), Error> {
+        let mut entries = self.storage.entries.lock().unwrap();
+        let entry_key = (self.user_id.clone(), key.to_string());
+        if entries.contains_key(&entry_key) {
+            return Err(format!("entry {key} already exists").into());
+        }
+        entries.insert(entry_key, payload.to_vec());
+        self.storage.updated.notify_waiters();
+        Ok(())
+    }
+
+    async fn update_entry(&self, key: &str, payload: &[u8]) -> Result<(), Error> {
+        self.storage
+            .entries
+            .lock()
+            .unwrap()
+            .insert((self.user_id.clone(), key.to_string()), payload.to_vec());
+        self.storage.updated.notify_waiters();
+        Ok(())
+    }
+
+    async fn delete_entry(&self, key: &str) -> Result<(), Error> {
+        match self
+            .storage
+            .entries
+            .lock()
+            .unwrap()
+            .remove(&(self.user_id.clone(), key.to_string()))
+        {
+            Some(_) => Ok(()),
+            None => Err(format!("entry {key} not found").into()),
+        }
+    }
+
+    async fn read_entry(&self, key: &str) -> Result<Vec<u8>, Error> {
+        match self
+            .storage
+            .entries
+            .lock()
+            .unwrap()
+            .get(&(self.user_id.clone(), key.to_string()))
+        {
+            Some(payload) => Ok(payload.clone()),
+            None => Err(format!("entry {key} not found").into()),
+        }
+    }
+
+    async fn read_or_wait(&self, key: &str) -> Result<Vec<u8>, Error> {
+        let entry_key = (self.user_id.clone(), key.to_string());
+        loop {
+            let updated = self.storage.updated.notified();
+            if let Some(payload) = self.storage.entries.lock().unwrap().get(&entry_key) {
+                return Ok(payload.clone());
+            }
+            updated.await;
+        }
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::interpreter::Interpreter;
+    use crate::sandbox::SandboxPolicy;
+    use crate::spec_parser::{parse_spec_from_toml, PackageSpec, ProtocolSpec};
+    use crate::validator::validate_package;
+    use std::{
+        path::{Path, PathBuf},
+        time::Duration,
+    };
+
+    pub(crate) const TASK_ID: &str = "test-task";
+
+    // a fresh directory for the working dirs of one test
+    pub(crate) fn test_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("playbook-{name}-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        dir
+    }
+
+    fn parse_protocol(dir: &Path, roles: &str) -> PackageSpec {
+        let toml_str = format!(
+            "[package]\nuse_playbook = true\n[t]\nname = \"t\"\nworkdir = \"{}/{{{{user_id}}}}\"\n{roles}",
+            dir.display()
+        );
+        parse_spec_from_toml(&toml_str).unwrap_or_else(|e| panic!("{e}"))
+    }
+
+    // protocol `t` with the `roles` tables, a playbook the operator would accept
+    pub(crate) fn protocol(dir: &Path, roles: &str) -> ProtocolSpec {
+        let mut package_spec = parse_protocol(dir, roles);
+        for error in validate_package(&package_spec) {
+            assert!(error.is_warning(), "{error}");
+        }
+        package_spec.remove(0)
+    }
+
+    // like `protocol`, for a playbook the validator rejects, to check that the
+    // interpreter fails it as well
+    pub(crate) fn unchecked_protocol(dir: &Path, roles: &str) -> ProtocolSpec {
+        parse_protocol(dir, roles).remove(0)
+    }
+
+    pub(crate) fn participant(user_id: &str, role: &str) -> Participant {
+        Participant {
+            user_id: user_id.to_string(),
+            role: role.to_string(),
+        }
+    }
+
+    pub(crate) async fn run(
+        protocol_spec: &ProtocolSpec,
+        storage: &Arc<MemoryStorage>,
+        participant: &Participant,
+        participants: &[Participant],
+    ) -> Result<(), Error> {
+        let role = protocol_spec
+            .roles
+            .iter()
+            .find(|role| role.name == participant.role)
+            .unwrap()
+            .clone();
+        let interpreter = Interpreter::new(protocol_spec, role, SandboxPolicy::default());
+        let backend = MemoryBackend::new(storage.clone(), &participant.user_id, TASK_ID);
+        interpreter
+            .run(backend, Vec::new(), participants.to_vec())
+            .await
+    }
+
+    #[tokio::test]
+    async fn send_and_recv_variable() {
+        let dir = test_dir("send-recv");
+        let protocol_spec = protocol(
+            &dir,
+            r#"
+[[t.roles.a.playbook.steps]]
+step_name = "write"
+process = "echo hello > x"
+[[t.roles.a.playbook.steps]]
+process_wait = "write"
+[[t.roles.a.playbook.steps]]
+send_variable = "x"
+file = "x"
+to_role = "b"
+[[t.roles.b.playbook.steps]]
+recv_variable = "x"
+file = "y"
+from_role = "a"
+index = 0
+"#,
+        );
+        let storage = Arc::new(MemoryStorage::default());
+        let participants = [participant("a0", "a"), participant("b0", "b")];
+        let (a, b) = tokio::join!(
+            run(&protocol_spec, &storage, &participants[0], &participants),
+            run(&protocol_spec, &storage, &participants[1], &participants),
+        );
+        a.unwrap();
+        b.unwrap();
+        assert_eq!(
+            std::fs::read_to_string(dir.join("b0/y")).unwrap(),
+            "hello\n"
+        );
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn read_or_wait_entry_wakes_on_write() {
+        let dir = test_dir("read-or-wait");
+        let protocol_spec = protocol(
+            &dir,
+            r#"
+[[t.roles.a.playbook.steps]]
+read_or_wait_entry = "e"
+file = "e"
+timeout = "10s"
+"#,
+        );
+        let storage = Arc::new(MemoryStorage::default());
+        let participants = [participant("a0", "a")];
+        let writer = MemoryBackend::new(storage.clone(), "a0", TASK_ID);
+        let (res, _) = tokio::join!(
+            run(&protocol_spec, &storage, &participants[0], &participants),
+            async {
+                tokio::time::sleep(Duration::from_millis(100)).await;
+                writer.create_entry("e", b"written later").await.unwrap();
+            },
+        );
+        res.unwrap();
+        assert_eq!(
+            std::fs::read_to_string(dir.join("a0/e")).unwrap(),
+            "written later"
+        );
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn recv_variable_times_out() {
+        let dir = test_dir("recv-timeout");
+        let protocol_spec = protocol(
+            &dir,
+            r#"
+[[t.roles.a.playbook.steps]]
+step_name = "p"
+process = "true"
+process_wait = "p"
+[[t.roles.b.playbook.steps]]
+recv_variable = "never_sent"
+file = "y"
+from_role = "a"
+index = 0
+timeout = "100ms"
+"#,
+        );
+        let storage = Arc::new(MemoryStorage::default());
+        let participants = [participant("a0", "a"), participant("b0", "b")];
+        let e = run(&protocol_spec, &storage, &participants[1], &participants)
+            .await
+            .unwrap_err();
+        assert!(e.to_string().contains("timed out"), "{e}");
+        assert!(!dir.join("b0/y").exists());
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
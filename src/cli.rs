@@ -0,0 +1,167 @@
+use crate::sandbox::SandboxPolicy;
+use clap::{Args, CommandFactory, FromArgMatches, Parser, Subcommand};
+
+/// Offline tools for playbook configs. Without a subcommand, the playbook
+/// starts the protocols with the CoLink arguments (`--addr`, `--jwt`, ...).
+#[derive(Parser)]
+#[command(name = "colink-playbook")]
+pub struct Cli {
+    #[command(subcommand)]
+    pub command: Command,
+}
+
+#[derive(Subcommand)]
+pub enum Command {
+    /// Parse and validate a playbook config without connecting to a CoLink server
+    #[command(alias = "lint")]
+    Validate {
+        /// Path of the playbook config
+        #[arg(env = "COLINK_PLAYBOOK_CONFIG", default_value = "colink.toml")]
+        config: String,
+        /// Fail on warnings as well as errors
+        #[arg(long)]
+        deny_warnings: bool,
+    },
+    /// Print the steps of one role with all templates rendered, without running anything
+    DryRun {
+        /// Path of the playbook config
+        #[arg(env = "COLINK_PLAYBOOK_CONFIG", default_value = "colink.toml")]
+        config: String,
+        /// Name of the protocol (the `name` field, not the table key)
+        #[arg(long)]
+        protocol: String,
+        /// Role to render the steps of
+        #[arg(long)]
+        role: String,
+        /// User id substituted for `{{user_id}}`
+        #[arg(long, default_value = "dry-run-user-id")]
+        user_id: String,
+        /// Task id substituted for `{{task_id}}`, as long as the UUIDs of CoLink
+        #[arg(long, default_value = "00000000-0000-0000-0000-000000000000")]
+        task_id: String,
+        /// Participants of the task, defaults to only this user in this role
+        #[arg(long = "participant", value_name = "USER_ID:ROLE")]
+        participants: Vec<String>,
+    },
+    /// Run every role of a protocol in this process, without a CoLink server
+    LocalRun {
+        /// Path of the playbook config
+        #[arg(env = "COLINK_PLAYBOOK_CONFIG", default_value = "colink.toml")]
+        config: String,
+        /// Name of the protocol (the `name` field, not the table key)
+        #[arg(long)]
+        protocol: String,
+        /// Number of participants of a role, defaults to `min_num` or 1 for each role
+        #[arg(long = "role", value_name = "ROLE=NUM")]
+        roles: Vec<String>,
+        /// File holding the param of the task
+        #[arg(long)]
+        param_file: Option<String>,
+        #[command(flatten)]
+        sandbox: SandboxArgs,
+    },
+}
+
+#[derive(Args)]
+pub struct SandboxArgs {
+    /// Run every sub-process in the sandbox, whatever the playbooks set
+    #[arg(long, env = "COLINK_PLAYBOOK_SANDBOX")]
+    pub sandbox: bool,
+    /// With --sandbox, let the playbooks give sub-processes the network with `sandbox_network`
+    #[arg(long, env = "COLINK_PLAYBOOK_SANDBOX_NETWORK", requires = "sandbox")]
+    pub sandbox_network: bool,
+}
+
+impl SandboxArgs {
+    pub fn policy(&self) -> SandboxPolicy {
+        SandboxPolicy {
+            force: self.sandbox,
+            allow_network: self.sandbox_network,
+        }
+    }
+}
+
+impl Cli {
+    /// Whether the command line should be handled here instead of by CoLink.
+    pub fn is_subcommand(arg: &str) -> bool {
+        arg == "help" || Cli::command().find_subcommand(arg).is_some()
+    }
+}
+
+// The arguments of `colink::_colink_parse_args`, parsed here so the playbook
+// can set up tracing itself, and the options of the playbook operator.
+#[derive(Parser)]
+#[command(
+    name = "colink-playbook",
+    about = "Run the protocols of a playbook config as a CoLink operator"
+)]
+pub struct OperatorArgs {
+    /// Address of CoLink server
+    #[arg(short, long, env = "COLINK_CORE_ADDR")]
+    pub addr: String,
+    /// User JWT
+    #[arg(short, long, env = "COLINK_JWT")]
+    pub jwt: String,
+    /// Path to CA certificate.
+    #[arg(long, env = "COLINK_CA_CERT")]
+    pub ca: Option<String>,
+    /// Path to client certificate.
+    #[arg(long, env = "COLINK_CLIENT_CERT")]
+    pub cert: Option<String>,
+    /// Path to private key.
+    #[arg(long, env = "COLINK_CLIENT_KEY")]
+    pub key: Option<String>,
+    /// Keep alive when disconnect.
+    #[arg(long, env = "COLINK_KEEP_ALIVE_WHEN_DISCONNECT")]
+    pub keep_alive_when_disconnect: bool,
+    /// Public address for the variable transfer inbox.
+    #[arg(long, env = "COLINK_VT_PUBLIC_ADDR")]
+    pub vt_public_addr: Option<String>,
+    /// Serve Prometheus metrics on http://<ADDR>/metrics, e.g. 127.0.0.1:9100
+    #[arg(long, env = "COLINK_PLAYBOOK_METRICS_ADDR", value_name = "ADDR")]
+    pub metrics_addr: Option<String>,
+    #[command(flatten)]
+    pub sandbox: SandboxArgs,
+}
+
+impl OperatorArgs {
+    /// Parse the arguments of the operator, with the subcommands of `Cli`
+    /// listed in `--help`.
+    pub fn parse_with_subcommands() -> OperatorArgs {
+        let mut commands = "Commands:\n".to_string();
+        for command in Cli::command().get_subcommands() {
+            let about = command.get_about().map(|about| about.to_string());
+            commands.push_str(&format!(
+                "  {:<10} {}\n",
+                command.get_name(),
+                about.unwrap_or_default()
+            ));
+        }
+        commands.push_str(
+            "  help       Print the help of the commands, or of one with `help <COMMAND>`",
+        );
+        let matches = OperatorArgs::command().after_help(commands).get_matches();
+        OperatorArgs::from_arg_matches(&matches).unwrap_or_else(|e| e.exit())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn commands() {
+        Cli::command().debug_assert();
+        OperatorArgs::command().debug_assert();
+    }
+
+    #[test]
+    fn subcommands() {
+        for arg in ["validate", "lint", "dry-run", "local-run", "help"] {
+            assert!(Cli::is_subcommand(arg), "{arg}");
+        }
+        for arg in ["--addr", "--help", "colink.toml"] {
+            assert!(!Cli::is_subcommand(arg), "{arg}");
+        }
+    }
+}
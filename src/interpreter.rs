@@ -1,61 +1,425 @@
 use std::{
+    collections::BTreeMap,
     env,
-    io::{Read, Write},
-    os::unix::process::ExitStatusExt,
-    path::PathBuf,
+    io::{BufRead, Read, Write},
+    os::unix::process::CommandExt,
+    path::{Path, PathBuf},
     process::Stdio,
+    sync::Mutex,
+    time::{Duration, Instant},
 };
 
-use crate::spec_parser::{RoleSpec, StepSpec};
+use crate::backend::Backend;
+use crate::limits::{enter_cgroup, remove_cgroup, Cgroup, ResourceLimits};
+use crate::metrics::{
+    ENTRY_OPERATIONS, METRICS, STEP_DURATION, TASKS_COMPLETED, TASKS_FAILED, TASKS_STARTED,
+    VARIABLE_BYTES_RECEIVED, VARIABLE_BYTES_SENT,
+};
+use crate::outcome::ProcessOutcome;
+use crate::redact::{Redactor, REDACTED};
+use crate::sandbox::{Sandbox, SandboxPolicy};
+use crate::spec_parser::{
+    parse_duration, parse_signal, shell_argv, signal_name, template_regex, Backoff, ExitCodeFormat,
+    LimitsSpec, ProtocolSpec, RetryOn, RoleSpec, StepSpec,
+};
+use crate::status::{status_entry_key, unix_millis, StepStatus, TaskState, TaskStatus};
 use colink::{CoLink, Participant, ProtocolEntry};
 use regex::Regex;
 use serde_json::json;
+use tracing::Instrument;
+
+fn render_template(
+    s: &str,
+    user_id: &str,
+    task_id: &str,
+) -> Result<String, Box<dyn std::error::Error + Send + Sync + 'static>> {
+    let mut rendered = String::new();
+    let mut last = 0;
+    for caps in template_regex().captures_iter(s) {
+        let template = caps.get(0).unwrap();
+        let value = match &caps[1] {
+            "user_id" => user_id,
+            "task_id" => task_id,
+            _ => {
+                return Err(format!(
+                    "unknown template variable `{}`, expected `task_id` or `user_id`",
+                    template.as_str()
+                )
+                .into())
+            }
+        };
+        let bound = |i: usize, default: usize| match caps.get(i) {
+            Some(bound) => bound.as_str().parse::<usize>().unwrap_or(usize::MAX),
+            None => default,
+        };
+        let slice = value
+            .get(bound(4, 0)..bound(5, value.len()))
+            .ok_or_else(|| {
+                format!(
+                    "`{}` is out of the {} characters of `{value}`",
+                    template.as_str(),
+                    value.len()
+                )
+            })?;
+        rendered.push_str(&s[last..template.start()]);
+        rendered.push_str(slice);
+        last = template.end();
+    }
+    rendered.push_str(&s[last..]);
+    Ok(rendered)
+}
+
+fn replace_env_var(s: &str) -> Result<String, Box<dyn std::error::Error + Send + Sync + 'static>> {
+    let re = Regex::new(r"\$(\w+)").unwrap();
+    let replaced_path = re.replace_all(s, |caps: &regex::Captures| {
+        let var_name = &caps[1];
+        match env::var(var_name) {
+            Ok(val) => val,
+            Err(_) => caps[0].to_string(),
+        }
+    });
+    Ok(replaced_path.to_string())
+}
+
+/// Failures of a step that a `retry` policy can match on.
+#[derive(Debug)]
+enum StepFailure {
+    Process {
+        outcome: ProcessOutcome,
+        message: String,
+    },
+    CoLink(String),
+}
+
+impl StepFailure {
+    fn colink(e: Box<dyn std::error::Error + Send + Sync + 'static>) -> StepFailure {
+        StepFailure::CoLink(e.to_string())
+    }
+}
+
+impl std::fmt::Display for StepFailure {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            StepFailure::Process { message, .. } => write!(f, "{message}"),
+            StepFailure::CoLink(message) => write!(f, "{message}"),
+        }
+    }
+}
+
+impl std::error::Error for StepFailure {}
+
+// Check how a process ended against `check_exit_code`, `check_signal` or
+// `expect_success` of the step, a mismatch is only logged with `allow_failure`.
+fn check_outcome(
+    step_spec: &StepSpec,
+    label: &str,
+    process_name: &str,
+    outcome: ProcessOutcome,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync + 'static>> {
+    let (matched, expected) = if let Some(check) = &step_spec.check_exit_code {
+        let matched = match outcome {
+            ProcessOutcome::Exited { code } => check.matches(code)?,
+            // a killed process is matched by the number of its signal, as
+            // before `check_signal`
+            ProcessOutcome::Signaled { signal } if step_spec.process_kill.is_some() => {
+                check.matches(signal)?
+            }
+            _ => false,
+        };
+        (matched, check.to_string())
+    } else if let Some(signal) = &step_spec.check_signal {
+        let signal = parse_signal(signal)?;
+        (
+            outcome == ProcessOutcome::Signaled { signal },
+            signal_name(signal),
+        )
+    } else if step_spec.expect_success == Some(true) {
+        (outcome.is_success(), "success".to_string())
+    } else {
+        return Ok(());
+    };
+    if matched {
+        return Ok(());
+    }
+    let message = format!("playbook: {label} {process_name} {outcome}, but expect {expected}");
+    if step_spec.allow_failure == Some(true) {
+        tracing::warn!("{message}, ignored with `allow_failure`");
+        return Ok(());
+    }
+    Err(StepFailure::Process { outcome, message }.into())
+}
+
+async fn with_timeout<T>(
+    timeout: Option<Duration>,
+    future: impl std::future::Future<
+        Output = Result<T, Box<dyn std::error::Error + Send + Sync + 'static>>,
+    >,
+    describe: impl FnOnce() -> String,
+) -> Result<T, Box<dyn std::error::Error + Send + Sync + 'static>> {
+    match timeout {
+        Some(timeout) => match tokio::time::timeout(timeout, future).await {
+            Ok(res) => res,
+            Err(_) => Err(format!("playbook: {} timed out after {:?}", describe(), timeout).into()),
+        },
+        None => future.await,
+    }
+}
+
+// The values of the environment variables named in `secrets` of the role: in
+// the `env` tables and in the environment of the operator.
+fn secret_values(
+    protocol_env: &BTreeMap<String, String>,
+    role_spec: &RoleSpec,
+    user_id: &str,
+    task_id: &str,
+) -> Vec<String> {
+    let secrets = match &role_spec.secrets {
+        Some(secrets) => secrets,
+        None => return Vec::new(),
+    };
+    let env_tables = [protocol_env, &role_spec.env]
+        .into_iter()
+        .chain(role_spec.steps.iter().filter_map(|step| step.env.as_ref()));
+    let mut values = Vec::new();
+    for env_table in env_tables {
+        for (name, value) in env_table {
+            if secrets.contains(name) {
+                values.extend(render_template(value, user_id, task_id).ok());
+            }
+        }
+    }
+    values.extend(secrets.iter().filter_map(|name| env::var(name).ok()));
+    values
+}
+
+// Whether the variable `name` is kept by the pattern of `env_allow`.
+fn env_name_matches(pattern: &str, name: &str) -> bool {
+    match pattern.strip_suffix('*') {
+        Some(prefix) => name.starts_with(prefix),
+        None => name == pattern,
+    }
+}
+
+// Whether the processes of the step run in the sandbox, see
+// `SandboxPolicy::resolve`.
+fn step_sandbox(policy: SandboxPolicy, role: &RoleSpec, step: &StepSpec) -> Option<bool> {
+    policy.resolve(
+        step.sandbox.or(role.sandbox).unwrap_or(false),
+        step.sandbox_network
+            .or(role.sandbox_network)
+            .unwrap_or(false),
+    )
+}
+
+// A command line showing the arguments of a process, quoted where needed.
+fn format_argv(argv: &[String]) -> String {
+    let plain = Regex::new(r"^[\w@%+=:,./-]+$").unwrap();
+    argv.iter()
+        .map(|arg| {
+            if plain.is_match(arg) {
+                arg.clone()
+            } else {
+                format!("{arg:?}")
+            }
+        })
+        .collect::<Vec<String>>()
+        .join(" ")
+}
+
+// Send `signal` to the process group `pgid`.
+fn signal_group(pgid: libc::pid_t, signal: libc::c_int) -> std::io::Result<()> {
+    if unsafe { libc::kill(-pgid, signal) } == -1 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(())
+}
 
-struct Context {
+// Send `signal` to the process group led by `child`, see `Context::run`.
+fn signal_process_group(child: &std::process::Child, signal: libc::c_int) -> std::io::Result<()> {
+    signal_group(child.id() as libc::pid_t, signal)
+}
+
+// how often a process is checked while it is waited
+const PROCESS_POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+// time the output of a process that exited is still read before the step
+// goes on
+const LOG_DRAIN_TIMEOUT: Duration = Duration::from_secs(1);
+
+// time processes left running at the end of a task get to exit after SIGTERM
+const DEFAULT_GRACE_PERIOD: Duration = Duration::from_secs(5);
+
+// A process group sent SIGTERM by `Context::signal_terminate`.
+struct Terminating {
+    name: String,
+    pgid: libc::pid_t,
+    // the process leading the group, unless it was already joined
+    process: Option<Process>,
+}
+
+// One check of a group sent SIGTERM: whether it was killed at `deadline` once
+// it is gone, `None` while some of its processes run.
+fn reap_terminated(terminating: &mut Terminating, deadline: Instant) -> Option<bool> {
+    if let Some(process) = &mut terminating.process {
+        let _ = process.child.try_wait();
+    }
+    // signal 0 only checks that some process of the group is left
+    if signal_group(terminating.pgid, 0).is_err() {
+        return Some(false);
+    }
+    if Instant::now() >= deadline {
+        let _ = signal_group(terminating.pgid, libc::SIGKILL);
+        if let Some(process) = &mut terminating.process {
+            let _ = process.child.wait();
+        }
+        return Some(true);
+    }
+    None
+}
+
+fn log_terminated(terminating: &Terminating, killed: bool) {
+    let Terminating {
+        name,
+        pgid,
+        process,
+    } = terminating;
+    match process {
+        Some(process) => {
+            tracing::warn!(
+                process = %name,
+                pid = pgid,
+                killed,
+                "process was still running at the end of the task, terminated"
+            );
+            if let Some(cgroup) = &process.cgroup {
+                remove_cgroup(cgroup);
+            }
+        }
+        None => tracing::warn!(
+            process = %name,
+            pgid,
+            killed,
+            "processes started by the joined process were still running at the end of the task, terminated"
+        ),
+    }
+}
+
+fn role_working_dir(role_spec: &RoleSpec, default_working_dir: &str) -> String {
+    match role_spec.workdir.clone() {
+        Some(role_dir) => role_dir + "/",
+        None => default_working_dir.to_string() + "/",
+    }
+}
+
+// What a process reads on stdin.
+enum Input {
+    Null,
+    File(PathBuf),
+    Bytes(Vec<u8>),
+}
+
+// How to start a process, see `Context::run`.
+struct ProcessCommand {
+    argv: Vec<String>,
+    env: BTreeMap<String, String>,
+    stdin: Input,
+    limits: LimitsSpec,
+    // in the sandbox if set, with the network if true
+    sandbox: Option<bool>,
+}
+
+struct Process {
+    child: std::process::Child,
+    // the cgroup made for the process, removed once it is waited
+    cgroup: Option<PathBuf>,
+    // threads copying the output of the child into tracing, joined on wait
+    log_threads: Vec<std::thread::JoinHandle<std::io::Result<()>>>,
+}
+
+struct Context<B: Backend> {
+    protocol_name: String,
+    protocol_env: BTreeMap<String, String>,
     role_spec: RoleSpec,
     working_dir: String,
+    sandbox_policy: SandboxPolicy,
     participants: Vec<Participant>,
     param: Vec<u8>,
-    cl: CoLink,
-    step_name_to_process: std::collections::HashMap<String, std::process::Child>,
+    cl: B,
+    step_name_to_process: std::collections::HashMap<String, Process>,
+    // the groups of the processes that were joined while processes they
+    // started still ran, terminated at the end of the task
+    joined_groups: Vec<(String, libc::pid_t)>,
     step_counter: i64,
+    status: TaskStatus,
+    redactor: Redactor,
 }
 
-impl Context {
+impl<B: Backend> Context<B> {
     pub fn new(
-        role_spec: RoleSpec,
-        default_working_dir: &str,
+        interpreter: &Interpreter,
         participants: &[Participant],
         param: &[u8],
-        cl: CoLink,
-    ) -> Context {
-        let work_dir = match role_spec.workdir.clone() {
-            Some(role_dir) => role_dir + "/",
-            None => default_working_dir.to_string() + "/",
-        };
+        cl: B,
+    ) -> Context<B> {
+        let role_spec = interpreter.role.clone();
+        let work_dir = role_working_dir(&role_spec, &interpreter.working_dir);
+        let user_id = cl.get_user_id().unwrap();
+        let task_id = cl.get_task_id().unwrap();
+        let status = TaskStatus::new(
+            &interpreter.protocol_name,
+            &role_spec.name,
+            &user_id,
+            &task_id,
+        );
+        let redactor = Redactor::new(
+            secret_values(&interpreter.protocol_env, &role_spec, &user_id, &task_id)
+                .into_iter()
+                .chain(cl.get_jwt()),
+        );
         Context {
+            protocol_name: interpreter.protocol_name.clone(),
+            protocol_env: interpreter.protocol_env.clone(),
             role_spec,
             working_dir: work_dir,
+            sandbox_policy: interpreter.sandbox_policy,
             participants: participants.to_vec(),
             param: param.to_vec(),
             cl,
             step_name_to_process: std::collections::HashMap::new(),
+            joined_groups: Vec::new(),
             step_counter: 0,
+            status,
+            redactor,
         }
     }
 
-    fn replace_env_var(
-        s: &str,
-    ) -> Result<String, Box<dyn std::error::Error + Send + Sync + 'static>> {
-        let re = Regex::new(r"\$(\w+)").unwrap();
-        let replaced_path = re.replace_all(s, |caps: &regex::Captures| {
-            let var_name = &caps[1];
-            match env::var(var_name) {
-                Ok(val) => val,
-                Err(_) => caps[0].to_string(),
-            }
-        });
-        Ok(replaced_path.to_string())
+    fn metric_labels(&self) -> [(&'static str, &str); 2] {
+        [
+            ("protocol", &self.protocol_name),
+            ("role", &self.role_spec.name),
+        ]
+    }
+
+    fn count_entry_operation(&self, operation: &str) {
+        let [protocol, role] = self.metric_labels();
+        METRICS.add(
+            ENTRY_OPERATIONS,
+            &[protocol, role, ("operation", operation)],
+            1,
+        );
+    }
+
+    // Write the status of this task to its entry. The task goes on if this
+    // fails, the status is only for monitoring.
+    async fn publish_status(&self) {
+        let key = status_entry_key(&self.status.task_id, &self.status.role);
+        let res = match serde_json::to_vec(&self.status) {
+            Ok(payload) => self.cl.update_entry(&key, &payload).await,
+            Err(e) => Err(e.into()),
+        };
+        if let Err(e) = res {
+            tracing::warn!(error = %e, "failed to publish the task status to {key}");
+        }
     }
 
     fn render_template(
@@ -64,38 +428,91 @@ impl Context {
     ) -> Result<String, Box<dyn std::error::Error + Send + Sync + 'static>> {
         let user_id = self.cl.get_user_id().unwrap();
         let task_id = self.cl.get_task_id().unwrap();
-        let assignments = std::collections::HashMap::from([
-            ("user_id".to_string(), user_id),
-            ("task_id".to_string(), task_id),
+        render_template(s, &user_id, &task_id)
+    }
+
+    // The environment variables set for the processes of the step: who this
+    // participant is, then the `env` of the protocol, the role and the step.
+    fn process_env(
+        &self,
+        step_spec: &StepSpec,
+    ) -> Result<BTreeMap<String, String>, Box<dyn std::error::Error + Send + Sync + 'static>> {
+        let user_id = self.cl.get_user_id()?;
+        let role = &self.role_spec.name;
+        let participant_index = self
+            .participants
+            .iter()
+            .filter(|participant| &participant.role == role)
+            .position(|participant| participant.user_id == user_id)
+            .unwrap_or_default();
+        let param_file = std::path::absolute(self.render_path("param.json")?)?;
+        let mut env = BTreeMap::from([
+            ("COLINK_TASK_ID".to_string(), self.cl.get_task_id()?),
+            ("COLINK_USER_ID".to_string(), user_id),
+            ("COLINK_ROLE".to_string(), role.clone()),
+            (
+                "COLINK_PARTICIPANT_INDEX".to_string(),
+                participant_index.to_string(),
+            ),
+            (
+                "COLINK_PARAM_FILE".to_string(),
+                param_file.to_string_lossy().into_owned(),
+            ),
         ]);
+        let no_env = BTreeMap::new();
+        for level in [
+            &self.protocol_env,
+            &self.role_spec.env,
+            step_spec.env.as_ref().unwrap_or(&no_env),
+        ] {
+            for (name, value) in level {
+                env.insert(name.clone(), self.render_template(value)?);
+            }
+        }
+        Ok(env)
+    }
 
-        let re = Regex::new(r"\{\{(\w+)(\[((\d+)?..(\d+)?)?\])?\}\}").unwrap();
-        let ret = re.replace_all(s, |caps: &regex::Captures| {
-            let var_name = caps.get(1).unwrap().as_str();
-            let var_value = assignments.get(var_name).unwrap();
-            let low_bound = match caps.get(4) {
-                Some(low_bound) => low_bound.as_str().parse::<usize>().unwrap_or(0),
-                None => 0,
-            };
-            let high_bound = match caps.get(5) {
-                Some(high_bound) => high_bound
-                    .as_str()
-                    .parse::<usize>()
-                    .unwrap_or(var_value.len()),
-                None => var_value.len(),
-            };
-            var_value[low_bound..high_bound].to_string()
-        });
-        Ok(ret.to_string())
+    // The limits of the step, or else of its role.
+    fn process_limits(&self, step_spec: &StepSpec) -> LimitsSpec {
+        match &step_spec.limits {
+            Some(limits) => limits.or(&self.role_spec.limits),
+            None => self.role_spec.limits.clone(),
+        }
+    }
+
+    fn process_sandbox(&self, step_spec: &StepSpec) -> Option<bool> {
+        step_sandbox(self.sandbox_policy, &self.role_spec, step_spec)
+    }
+
+    // The program and arguments running a command of the step with the shell
+    // of the step or its role.
+    fn shell_argv(
+        &self,
+        step_spec: &StepSpec,
+        command: &str,
+    ) -> Result<Vec<String>, Box<dyn std::error::Error + Send + Sync + 'static>> {
+        let shell = step_spec.shell.as_ref().or(self.role_spec.shell.as_ref());
+        Ok(shell_argv(shell.map(String::as_str), command)?)
+    }
+
+    // relative paths are resolved against the working dir instead of the
+    // process cwd, which is shared by all the tasks running in this process
+    fn render_path(
+        &self,
+        file_name: &str,
+    ) -> Result<PathBuf, Box<dyn std::error::Error + Send + Sync + 'static>> {
+        let rendered_path = self.render_template(file_name)?;
+        let replaced_path = replace_env_var(&rendered_path)?;
+        Ok(PathBuf::from(&self.working_dir).join(replaced_path))
     }
 
     fn render_path_and_open(
         &self,
         file_name: String,
     ) -> Result<Box<std::fs::File>, Box<dyn std::error::Error + Send + Sync + 'static>> {
-        let rendered_path = self.render_template(&file_name).unwrap();
-        let replaced_path = Self::replace_env_var(&rendered_path).unwrap();
-        let file = std::fs::File::open(replaced_path).unwrap();
+        let path = self.render_path(&file_name)?;
+        let file = std::fs::File::open(&path)
+            .map_err(|e| format!("playbook: failed to open {}: {e}", path.display()))?;
         Ok(Box::new(file))
     }
 
@@ -103,12 +520,12 @@ impl Context {
         &self,
         file_name: String,
     ) -> Result<Box<std::fs::File>, Box<dyn std::error::Error + Send + Sync + 'static>> {
-        let rendered_path = self.render_template(&file_name).unwrap();
-        let replaced_path = Self::replace_env_var(&rendered_path).unwrap();
-        let path = PathBuf::from(replaced_path.to_string());
-        let parent = path.parent().unwrap();
-        std::fs::create_dir_all(parent)?;
-        let file = std::fs::File::create(replaced_path).unwrap();
+        let path = self.render_path(&file_name)?;
+        let file = path
+            .parent()
+            .map_or(Ok(()), std::fs::create_dir_all)
+            .and_then(|()| std::fs::File::create(&path))
+            .map_err(|e| format!("playbook: failed to create {}: {e}", path.display()))?;
         Ok(Box::new(file))
     }
 
@@ -146,83 +563,405 @@ impl Context {
             "user_id":self.cl.get_user_id().unwrap(),
             "task_id":self.cl.get_task_id().unwrap(),
         });
-        let mut file = self.render_path_and_create(file_name).unwrap();
+        let mut file = self.render_path_and_create(file_name)?;
         serde_json::to_writer(&mut file, &param_json)?;
         Ok(())
     }
 
+    // The files receiving the output of process `step_name`: set on the step
+    // starting it, or else on the first step from here that waits or kills it.
+    fn output_files(
+        &self,
+        step_spec: &StepSpec,
+        step_name: &str,
+    ) -> (Option<String>, Option<String>) {
+        if step_spec.stdout_file.is_some() || step_spec.stderr_file.is_some() {
+            return (step_spec.stdout_file.clone(), step_spec.stderr_file.clone());
+        }
+        let later_steps = self
+            .role_spec
+            .steps
+            .iter()
+            .skip(self.step_counter as usize + 1);
+        for later_step in later_steps {
+            if later_step.process_wait.as_deref() == Some(step_name)
+                || later_step.process_kill.as_deref() == Some(step_name)
+                || later_step.process_stop.as_deref() == Some(step_name)
+            {
+                return (
+                    later_step.stdout_file.clone(),
+                    later_step.stderr_file.clone(),
+                );
+            }
+        }
+        (None, None)
+    }
+
+    fn open_output(
+        &self,
+        file_name: &Option<String>,
+    ) -> Result<Stdio, Box<dyn std::error::Error + Send + Sync + 'static>> {
+        match file_name {
+            Some(file_name) => Ok(Stdio::from(
+                *self.render_path_and_create(file_name.to_string())?,
+            )),
+            None => Ok(Stdio::null()),
+        }
+    }
+
+    // Copy the output of a child line by line into its file (if any) and
+    // into tracing events, until the child closes it.
+    fn spawn_log_thread(
+        &self,
+        step_name: &str,
+        stream: &'static str,
+        output: impl Read + Send + 'static,
+        file_name: &Option<String>,
+    ) -> Result<
+        std::thread::JoinHandle<std::io::Result<()>>,
+        Box<dyn std::error::Error + Send + Sync + 'static>,
+    > {
+        let mut file = match file_name {
+            Some(file_name) => Some(self.render_path_and_create(file_name.to_string())?),
+            None => None,
+        };
+        let protocol = self.protocol_name.clone();
+        let role = self.role_spec.name.clone();
+        let task_id = self.cl.get_task_id().unwrap();
+        let step_name = step_name.to_string();
+        let redactor = self.redactor.clone();
+        Ok(std::thread::spawn(move || {
+            let mut reader = std::io::BufReader::new(output);
+            let mut line = Vec::new();
+            loop {
+                line.clear();
+                if reader.read_until(b'\n', &mut line)? == 0 {
+                    return Ok(());
+                }
+                if let Some(file) = &mut file {
+                    file.write_all(&line)?;
+                }
+                tracing::info!(
+                    protocol = %protocol,
+                    role = %role,
+                    task_id = %task_id,
+                    step_name = %step_name,
+                    stream,
+                    "{}",
+                    redactor.redact(String::from_utf8_lossy(&line).trim_end())
+                );
+            }
+        }))
+    }
+
     fn run(
         &mut self,
         step_name: &str,
-        process_command: &str,
+        process_command: ProcessCommand,
+        stdout_file: &Option<String>,
+        stderr_file: &Option<String>,
+        log_output: bool,
     ) -> Result<(), Box<dyn std::error::Error + Send + Sync + 'static>> {
-        let rendered_path = self.render_template(&self.working_dir).unwrap();
-        let working_dir = Self::replace_env_var(&rendered_path).unwrap();
-        let mut bind = std::process::Command::new("bash");
-        let command = bind.arg("-c").arg(process_command);
+        let ProcessCommand {
+            argv,
+            env,
+            stdin,
+            limits,
+            sandbox,
+        } = process_command;
+        // the running one would be lost, and never terminated
+        if self.step_name_to_process.contains_key(step_name) {
+            return Err(format!("playbook: process {step_name} is already running").into());
+        }
+        let working_dir = &self.working_dir;
+        let mut bind = std::process::Command::new(&argv[0]);
+        let command = bind.args(&argv[1..]);
         command.current_dir(working_dir);
-        command.stdout(Stdio::piped());
-        command.stderr(Stdio::piped());
-        let core_addr = self.cl.get_core_addr().unwrap();
-        let user_jwt = self.cl.get_jwt().unwrap();
-        command
-            .env("COLINK_CORE_ADDR", core_addr)
-            .env("COLINK_JWT", user_jwt);
-        self.step_name_to_process
-            .insert(step_name.to_string(), command.spawn()?);
+        // never the stdin of the operator, a process waiting for input there
+        // would hang the task
+        command.stdin(match &stdin {
+            Input::Null => Stdio::null(),
+            Input::File(path) => Stdio::from(std::fs::File::open(path).map_err(|e| {
+                format!(
+                    "playbook: failed to open the stdin of process {step_name} {}: {e}",
+                    path.display()
+                )
+            })?),
+            Input::Bytes(_) => Stdio::piped(),
+        });
+        // lead a new process group, so that signals reach everything it starts
+        command.process_group(0);
+        if log_output {
+            command.stdout(Stdio::piped());
+            command.stderr(Stdio::piped());
+        } else {
+            // written while the process runs, so a full pipe never blocks it
+            command.stdout(self.open_output(stdout_file)?);
+            command.stderr(self.open_output(stderr_file)?);
+        }
+        if self.role_spec.env_clear == Some(true) {
+            command.env_clear();
+            let env_allow = self.role_spec.env_allow.clone().unwrap_or_default();
+            command.envs(env::vars().filter(|(name, _)| {
+                env_allow
+                    .iter()
+                    .any(|pattern| env_name_matches(pattern, name))
+            }));
+        }
+        // the memory backend has no server to connect to
+        if let Ok(core_addr) = self.cl.get_core_addr() {
+            command.env("COLINK_CORE_ADDR", core_addr);
+            if self.role_spec.pass_jwt == Some(true) {
+                command.env("COLINK_JWT", self.cl.get_jwt()?);
+            }
+        }
+        command.envs(&env);
+        let resource_limits = ResourceLimits::new(&limits)?;
+        let cgroup = if limits.memory_max.is_some() || limits.cpu_max.is_some() {
+            let task_id = self.cl.get_task_id()?;
+            // the process does not run without the limits the playbook asked for
+            let cgroup =
+                Cgroup::create(&format!("{task_id}-{step_name}"), &limits).map_err(|e| {
+                    format!("playbook: cannot apply the cgroup limits of process {step_name}: {e}")
+                })?;
+            Some(cgroup)
+        } else {
+            None
+        };
+        let procs_fd = cgroup.as_ref().map(Cgroup::procs_fd);
+        let sandbox = match sandbox {
+            Some(network) => Some(Sandbox::new(Path::new(working_dir), network)?),
+            None => None,
+        };
+        let sandboxed = sandbox.is_some();
+        if !resource_limits.is_empty() || procs_fd.is_some() || sandboxed {
+            // the cgroup is entered before exec, so that it holds everything
+            // the process starts, and the sandbox last, as a lower nice level
+            // is not allowed in it
+            unsafe {
+                command.pre_exec(move || {
+                    if let Some(procs_fd) = procs_fd {
+                        enter_cgroup(procs_fd)?;
+                    }
+                    resource_limits.apply()?;
+                    if let Some(sandbox) = &sandbox {
+                        sandbox.enter()?;
+                    }
+                    Ok(())
+                });
+            }
+        }
+        let mut child = command.spawn().map_err(|e| {
+            format!(
+                "playbook: failed to start process {step_name} ({}){}: {e}",
+                argv[0],
+                if sandboxed { " in the sandbox" } else { "" }
+            )
+        })?;
+        if let Input::Bytes(bytes) = stdin {
+            let mut child_stdin = child.stdin.take().unwrap();
+            // not joined, the process may exit without reading all of it
+            std::thread::spawn(move || {
+                if let Err(e) = child_stdin.write_all(&bytes) {
+                    if e.kind() != std::io::ErrorKind::BrokenPipe {
+                        tracing::warn!(error = %e, "failed to write the stdin of a process");
+                    }
+                }
+            });
+        }
+        let mut log_threads = Vec::new();
+        if log_output {
+            let stdout = child.stdout.take().unwrap();
+            let stderr = child.stderr.take().unwrap();
+            log_threads.push(self.spawn_log_thread(step_name, "stdout", stdout, stdout_file)?);
+            log_threads.push(self.spawn_log_thread(step_name, "stderr", stderr, stderr_file)?);
+        }
+        METRICS.add_processes(1);
+        self.step_name_to_process.insert(
+            step_name.to_string(),
+            Process {
+                child,
+                cgroup: cgroup.map(|cgroup| cgroup.path),
+                log_threads,
+            },
+        );
         Ok(())
     }
 
-    fn wait(
+    // The process started by the step `process_name`, an error if it is not
+    // running, e.g. it was already waited by an earlier step.
+    fn running_process(
+        &mut self,
+        process_name: &str,
+    ) -> Result<&mut Process, Box<dyn std::error::Error + Send + Sync + 'static>> {
+        self.step_name_to_process
+            .get_mut(process_name)
+            .ok_or_else(|| format!("playbook: process {process_name} is not running").into())
+    }
+
+    // Wait for the process to exit, and kill its group if it is still running
+    // after `timeout`. Returns how it ended and whether it was killed.
+    async fn wait_or_kill(
         &mut self,
         process_name: &String,
-        stdout_file: &Option<String>,
-        stderr_file: &Option<String>,
-        exit_code: &Option<String>,
-    ) -> Result<i32, Box<dyn std::error::Error + Send + Sync + 'static>> {
-        let mut child = self.step_name_to_process.remove(process_name).unwrap();
-        let exit_status = match child.try_wait() {
-            Ok(Some(status)) => status,
-            Ok(None) => child.wait()?,
-            Err(e) => {
-                child.kill()?;
-                child.wait()?;
-                return Err(e.into());
+        timeout: Option<Duration>,
+    ) -> Result<(ProcessOutcome, bool), Box<dyn std::error::Error + Send + Sync + 'static>> {
+        let mut deadline = timeout.map(|timeout| Instant::now() + timeout);
+        let mut timed_out = false;
+        // polled, so that the runtime keeps running other tasks and the
+        // process stays in `step_name_to_process` until it exits, for
+        // `terminate_processes` if the task is cancelled meanwhile
+        let exit_status = loop {
+            let child = &mut self.running_process(process_name)?.child;
+            match child.try_wait() {
+                Ok(Some(status)) => break status,
+                Ok(None) if deadline.is_some_and(|deadline| Instant::now() >= deadline) => {
+                    timed_out = true;
+                    deadline = None;
+                    signal_process_group(child, libc::SIGKILL)?;
+                }
+                Ok(None) => tokio::time::sleep(PROCESS_POLL_INTERVAL).await,
+                Err(e) => {
+                    signal_process_group(child, libc::SIGKILL)?;
+                    child.wait()?;
+                    self.step_name_to_process.remove(process_name);
+                    METRICS.add_processes(-1);
+                    return Err(e.into());
+                }
             }
         };
-        let code = match exit_status.signal() {
-            Some(x) => x,
-            None => exit_status.code().unwrap(),
-        };
-        if let Some(stdout_file) = stdout_file {
-            let mut file = self
-                .render_path_and_create(stdout_file.to_string())
-                .unwrap();
-            let stdout = child.stdout.unwrap();
-            std::io::copy(&mut std::io::BufReader::new(stdout), &mut file)?;
+        let Process {
+            child,
+            cgroup,
+            log_threads,
+        } = self.step_name_to_process.remove(process_name).unwrap();
+        METRICS.add_processes(-1);
+        if signal_process_group(&child, 0).is_ok() {
+            self.joined_groups
+                .push((process_name.to_string(), child.id() as libc::pid_t));
+        }
+        if let Some(cgroup) = cgroup {
+            remove_cgroup(&cgroup);
         }
-        if let Some(stderr_file) = stderr_file {
-            let mut file = self
-                .render_path_and_create(stderr_file.to_string())
-                .unwrap();
-            let stderr = child.stderr.unwrap();
-            std::io::copy(&mut std::io::BufReader::new(stderr), &mut file)?;
+        // the output is read to its end unless a process the child started
+        // keeps it open, which must not hold up the step: such threads are
+        // left to copy the rest of it
+        let deadline = Instant::now() + LOG_DRAIN_TIMEOUT;
+        while log_threads
+            .iter()
+            .any(|log_thread| !log_thread.is_finished())
+            && Instant::now() < deadline
+        {
+            tokio::time::sleep(PROCESS_POLL_INTERVAL).await;
         }
-        if let Some(exit_code) = exit_code {
-            let mut file = self.render_path_and_create(exit_code.to_string()).unwrap();
-            file.write_all(format!("{}", code).as_bytes())?;
+        for log_thread in log_threads {
+            if log_thread.is_finished() {
+                log_thread.join().unwrap()?;
+            } else {
+                tracing::debug!(
+                    process = %process_name,
+                    "output still open after the process exited, not waited"
+                );
+            }
+        }
+        Ok((ProcessOutcome::from_status(exit_status), timed_out))
+    }
+
+    // Keep how a process joined or killed by `step_spec` ended in the task
+    // status and in the `exit_code` file of the step.
+    fn record_outcome(
+        &mut self,
+        step_spec: &StepSpec,
+        process_name: &str,
+        outcome: ProcessOutcome,
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync + 'static>> {
+        self.status
+            .process_outcomes
+            .insert(process_name.to_string(), outcome);
+        if let Some(exit_code) = &step_spec.exit_code {
+            let mut file = self.render_path_and_create(exit_code.to_string())?;
+            match step_spec.exit_code_format.unwrap_or(ExitCodeFormat::Code) {
+                ExitCodeFormat::Code => file.write_all(outcome.code().to_string().as_bytes())?,
+                ExitCodeFormat::Json => serde_json::to_writer(&mut file, &outcome)?,
+            }
+        }
+        Ok(())
+    }
+
+    fn role_grace_period(&self) -> Duration {
+        self.role_spec
+            .grace_period
+            .as_deref()
+            .and_then(|grace_period| parse_duration(grace_period).ok())
+            .unwrap_or(DEFAULT_GRACE_PERIOD)
+    }
+
+    // Send SIGTERM to the process groups still running at the end of the
+    // task, which are killed if they are still alive at the returned deadline.
+    fn signal_terminate(&mut self) -> (Vec<Terminating>, Instant) {
+        let grace_period = self.role_grace_period();
+        let mut running: Vec<Terminating> = Vec::new();
+        for (name, mut process) in self.step_name_to_process.drain() {
+            METRICS.add_processes(-1);
+            // reap the process if it exited, the rest of its group may still run
+            let _ = process.child.try_wait();
+            let pgid = process.child.id() as libc::pid_t;
+            if signal_group(pgid, libc::SIGTERM).is_ok() {
+                running.push(Terminating {
+                    name,
+                    pgid,
+                    process: Some(process),
+                });
+            }
+        }
+        for (name, pgid) in self.joined_groups.drain(..) {
+            if signal_group(pgid, libc::SIGTERM).is_ok() {
+                running.push(Terminating {
+                    name,
+                    pgid,
+                    process: None,
+                });
+            }
+        }
+        (running, Instant::now() + grace_period)
+    }
+
+    // Stop the processes still running at the end of the task with SIGTERM,
+    // and SIGKILL those still alive after the grace period of the role.
+    // Returns the names of the processes that were still running.
+    async fn terminate_processes(&mut self) -> Vec<String> {
+        let (running, deadline) = self.signal_terminate();
+        let mut terminated = Vec::new();
+        for mut terminating in running {
+            let killed = loop {
+                match reap_terminated(&mut terminating, deadline) {
+                    Some(killed) => break killed,
+                    None => tokio::time::sleep(PROCESS_POLL_INTERVAL).await,
+                }
+            };
+            log_terminated(&terminating, killed);
+            terminated.push(terminating.name);
+        }
+        terminated.sort();
+        terminated.dedup();
+        terminated
+    }
+
+    // how a step is named in error messages
+    fn step_label(&self, step_spec: &StepSpec) -> String {
+        match &step_spec.step_name {
+            Some(step_name) => step_name.clone(),
+            None => format!("steps[{}]", self.step_counter),
         }
-        Ok(code)
     }
 
     fn kill(
         &mut self,
-        process_name: &String,
+        process_name: &str,
+        signal: libc::c_int,
     ) -> Result<(), Box<dyn std::error::Error + Send + Sync + 'static>> {
-        let mut child = self.step_name_to_process.remove(process_name).unwrap();
-        child.kill()?;
-        self.step_name_to_process
-            .insert(process_name.clone(), child);
+        let process = self.running_process(process_name)?;
+        signal_process_group(&process.child, signal)?;
         Ok(())
     }
 
@@ -233,9 +972,7 @@ impl Context {
         to_role: &str,
         index: Option<usize>,
     ) -> Result<(), Box<dyn std::error::Error + Send + Sync + 'static>> {
-        let mut file = self
-            .render_path_and_open(variable_file.to_string())
-            .unwrap();
+        let mut file = self.render_path_and_open(variable_file.to_string())?;
         let mut payload = Vec::new();
         file.read_to_end(&mut payload)?;
         let total_participants: Vec<Participant> = self
@@ -250,7 +987,13 @@ impl Context {
         };
         self.cl
             .send_variable(variable_name, payload.as_slice(), participants.as_slice())
-            .await?;
+            .await
+            .map_err(StepFailure::colink)?;
+        METRICS.add(
+            VARIABLE_BYTES_SENT,
+            &self.metric_labels(),
+            (payload.len() * participants.len()) as u64,
+        );
         Ok(())
     }
 
@@ -270,11 +1013,15 @@ impl Context {
         let msg = self
             .cl
             .recv_variable(variable_name, &from_participants.as_slice()[index])
-            .await?;
+            .await
+            .map_err(StepFailure::colink)?;
+        METRICS.add(
+            VARIABLE_BYTES_RECEIVED,
+            &self.metric_labels(),
+            msg.len() as u64,
+        );
         if let Some(store_to_file) = variable_file {
-            let mut file = self
-                .render_path_and_create(store_to_file.to_string())
-                .unwrap();
+            let mut file = self.render_path_and_create(store_to_file.to_string())?;
             file.write_all(msg.as_slice())?;
         }
         Ok(())
@@ -285,10 +1032,14 @@ impl Context {
         key: &str,
         file_name: &str,
     ) -> Result<(), Box<dyn std::error::Error + Send + Sync + 'static>> {
-        let mut file = self.render_path_and_open(file_name.to_string()).unwrap();
+        let mut file = self.render_path_and_open(file_name.to_string())?;
         let mut payload = Vec::new();
         file.read_to_end(&mut payload)?;
-        self.cl.create_entry(key, payload.as_slice()).await?;
+        self.count_entry_operation("create");
+        self.cl
+            .create_entry(key, payload.as_slice())
+            .await
+            .map_err(StepFailure::colink)?;
         Ok(())
     }
 
@@ -296,7 +1047,11 @@ impl Context {
         &self,
         key: &str,
     ) -> Result<(), Box<dyn std::error::Error + Send + Sync + 'static>> {
-        self.cl.delete_entry(key).await?;
+        self.count_entry_operation("delete");
+        self.cl
+            .delete_entry(key)
+            .await
+            .map_err(StepFailure::colink)?;
         Ok(())
     }
 
@@ -305,10 +1060,14 @@ impl Context {
         key: &str,
         file_name: &str,
     ) -> Result<(), Box<dyn std::error::Error + Send + Sync + 'static>> {
-        let mut file = self.render_path_and_open(file_name.to_string()).unwrap();
+        let mut file = self.render_path_and_open(file_name.to_string())?;
         let mut payload = Vec::new();
         file.read_to_end(&mut payload)?;
-        self.cl.update_entry(key, payload.as_slice()).await?;
+        self.count_entry_operation("update");
+        self.cl
+            .update_entry(key, payload.as_slice())
+            .await
+            .map_err(StepFailure::colink)?;
         Ok(())
     }
 
@@ -317,8 +1076,9 @@ impl Context {
         key: &str,
         file_name: &str,
     ) -> Result<(), Box<dyn std::error::Error + Send + Sync + 'static>> {
-        let mut file = self.render_path_and_create(file_name.to_string()).unwrap();
-        let msg = self.cl.read_entry(key).await?;
+        let mut file = self.render_path_and_create(file_name.to_string())?;
+        self.count_entry_operation("read");
+        let msg = self.cl.read_entry(key).await.map_err(StepFailure::colink)?;
         file.write_all(msg.as_slice())?;
         Ok(())
     }
@@ -328,19 +1088,24 @@ impl Context {
         key: &str,
         file_name: &str,
     ) -> Result<(), Box<dyn std::error::Error + Send + Sync + 'static>> {
-        let mut file = self.render_path_and_create(file_name.to_string()).unwrap();
-        let msg = self.cl.read_or_wait(key).await?;
+        let mut file = self.render_path_and_create(file_name.to_string())?;
+        self.count_entry_operation("read_or_wait");
+        let msg = self
+            .cl
+            .read_or_wait(key)
+            .await
+            .map_err(StepFailure::colink)?;
         file.write_all(msg.as_slice())?;
         Ok(())
     }
 
-    async fn evaluate(
-        ctx: &mut Context,
+    // Run the `if` command of a step, false if the step should be skipped.
+    async fn check_if(
+        ctx: &mut Context<B>,
         step_spec: &StepSpec,
-    ) -> Result<(), Box<dyn std::error::Error + Send + Sync + 'static>> {
-        // check if
+    ) -> Result<bool, Box<dyn std::error::Error + Send + Sync + 'static>> {
         if let Some(if_command) = &step_spec._if {
-            let if_command = ctx.render_template(if_command).unwrap();
+            let if_command = ctx.render_template(if_command)?;
             let if_step_name = format!(
                 "__if_{}",
                 match &step_spec.step_name {
@@ -348,65 +1113,200 @@ impl Context {
                     None => ctx.step_counter.to_string(),
                 }
             );
-            ctx.run(&if_step_name, &if_command)?;
-            let result = ctx.wait(&if_step_name, &None, &None, &None)?;
-            if result != 0 {
-                return Ok(());
+            let if_command = ProcessCommand {
+                argv: ctx.shell_argv(step_spec, &if_command)?,
+                env: ctx.process_env(step_spec)?,
+                stdin: Input::Null,
+                limits: ctx.process_limits(step_spec),
+                sandbox: ctx.process_sandbox(step_spec),
+            };
+            ctx.run(&if_step_name, if_command, &None, &None, false)?;
+            let (outcome, _) = ctx.wait_or_kill(&if_step_name, None).await?;
+            return Ok(outcome.is_success());
+        }
+        Ok(true)
+    }
+
+    // Returns false if the step is skipped by its `if`.
+    async fn evaluate(
+        ctx: &mut Context<B>,
+        step_spec: &StepSpec,
+    ) -> Result<bool, Box<dyn std::error::Error + Send + Sync + 'static>> {
+        if !Self::check_if(ctx, step_spec).await? {
+            return Ok(false);
+        }
+        let retry = match &step_spec.retry {
+            Some(retry) => retry,
+            None => return Self::evaluate_once(ctx, step_spec).await.map(|()| true),
+        };
+        let mut delay = match &retry.delay {
+            Some(delay) => parse_duration(delay)?,
+            None => Duration::from_secs(1),
+        };
+        let max_delay = retry.max_delay.as_deref().map(parse_duration).transpose()?;
+        let mut attempt = 1;
+        loop {
+            let err = match Self::evaluate_once(ctx, step_spec).await {
+                Ok(()) => return Ok(true),
+                Err(err) => err,
+            };
+            let retryable = match err.downcast_ref::<StepFailure>() {
+                Some(StepFailure::Process { outcome, .. }) => {
+                    retry.retries_on(RetryOn::ExitCode)
+                        && retry.exit_codes.as_ref().is_none_or(|exit_codes| {
+                            matches!(outcome, ProcessOutcome::Exited { code } if exit_codes.contains(code))
+                        })
+                }
+                Some(StepFailure::CoLink(_)) => retry.retries_on(RetryOn::Colink),
+                None => false,
+            };
+            if !retryable {
+                return Err(err);
             }
+            if attempt >= retry.max_attempts {
+                return Err(format!("{err} (failed after {attempt} attempts)").into());
+            }
+            let error = ctx.redactor.redact(&err.to_string());
+            tracing::warn!(attempt, error, "step failed, retrying in {delay:?}");
+            tokio::time::sleep(delay).await;
+            if retry.backoff == Some(Backoff::Exponential) {
+                delay = delay.saturating_mul(2);
+                if let Some(max_delay) = max_delay {
+                    delay = delay.min(max_delay);
+                }
+            }
+            attempt += 1;
         }
+    }
+
+    async fn evaluate_once(
+        ctx: &mut Context<B>,
+        step_spec: &StepSpec,
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync + 'static>> {
+        let timeout = step_spec
+            .timeout
+            .as_deref()
+            .map(parse_duration)
+            .transpose()?;
         // normal action
-        if let Some(process_command) = &step_spec.process {
+        if step_spec.starts_process() {
             if let Some(step_name) = &step_spec.step_name {
-                let process_command = ctx.render_template(process_command).unwrap();
-                ctx.run(step_name, &process_command)?;
-                if step_spec.process_kill.is_none() && step_spec.process_wait.is_none() {
+                let argv = match &step_spec.argv {
+                    Some(argv) => argv
+                        .iter()
+                        .map(|arg| ctx.render_template(arg))
+                        .collect::<Result<Vec<String>, _>>()?,
+                    None => {
+                        let process_command =
+                            ctx.render_template(step_spec.process.as_ref().unwrap())?;
+                        ctx.shell_argv(step_spec, &process_command)?
+                    }
+                };
+                if argv.is_empty() {
+                    return Err("playbook: `argv` is empty".into());
+                }
+                let (stdout_file, stderr_file) = ctx.output_files(step_spec, step_name);
+                let log_output = step_spec
+                    .log_output
+                    .or(ctx.role_spec.log_output)
+                    .unwrap_or(false);
+                let stdin = match (&step_spec.stdin, &step_spec.stdin_file) {
+                    (Some(stdin), _) => Input::Bytes(ctx.render_template(stdin)?.into_bytes()),
+                    (None, Some(stdin_file)) => Input::File(ctx.render_path(stdin_file)?),
+                    (None, None) => Input::Null,
+                };
+                let process_command = ProcessCommand {
+                    argv,
+                    env: ctx.process_env(step_spec)?,
+                    stdin,
+                    limits: ctx.process_limits(step_spec),
+                    sandbox: ctx.process_sandbox(step_spec),
+                };
+                ctx.run(
+                    step_name,
+                    process_command,
+                    &stdout_file,
+                    &stderr_file,
+                    log_output,
+                )?;
+                if step_spec.process_kill.is_none()
+                    && step_spec.process_stop.is_none()
+                    && step_spec.process_wait.is_none()
+                {
                     return Ok(());
                 }
             } else {
-                return Err("playbook: `process` need `step_name`".into());
+                let field = if step_spec.argv.is_some() {
+                    "argv"
+                } else {
+                    "process"
+                };
+                return Err(format!("playbook: `{field}` need `step_name`").into());
             }
         }
         if let Some(process_kill) = &step_spec.process_kill {
-            ctx.kill(process_kill)?;
-            let exit_code = ctx.wait(
-                process_kill,
-                &step_spec.stdout_file,
-                &step_spec.stderr_file,
-                &step_spec.exit_code,
-            )?;
-            if let Some(check_code) = step_spec.check_exit_code {
-                if check_code != exit_code {
-                    return Err(format!(
-                        "playbook: process(killed) {} exits with {}, but expect {}",
-                        process_kill, exit_code, check_code
-                    )
-                    .into());
-                } else {
-                    return Ok(());
-                }
+            let signal = match &step_spec.signal {
+                Some(signal) => parse_signal(signal)?,
+                None => libc::SIGKILL,
+            };
+            // a signal other than SIGKILL can be caught or ignored, so the
+            // process is killed if it is still running after the grace period
+            let grace_period = match (signal, &step_spec.grace_period) {
+                (libc::SIGKILL, _) => None,
+                (_, Some(grace_period)) => Some(parse_duration(grace_period)?),
+                (_, None) => Some(ctx.role_grace_period()),
+            };
+            ctx.kill(process_kill, signal)?;
+            let (outcome, escalated) = ctx.wait_or_kill(process_kill, grace_period).await?;
+            if escalated {
+                tracing::warn!(
+                    process = %process_kill,
+                    "process did not exit within {:?} after the signal, killed",
+                    grace_period.unwrap_or_default()
+                );
+            }
+            ctx.record_outcome(step_spec, process_kill, outcome)?;
+            return check_outcome(step_spec, "process(killed)", process_kill, outcome);
+        }
+        if let Some(process_stop) = &step_spec.process_stop {
+            let signal = match &step_spec.signal {
+                Some(signal) => parse_signal(signal)?,
+                None => libc::SIGTERM,
+            };
+            let grace_period = match &step_spec.grace_period {
+                Some(grace_period) => parse_duration(grace_period)?,
+                None => ctx.role_grace_period(),
+            };
+            ctx.kill(process_stop, signal)?;
+            let (outcome, escalated) = ctx.wait_or_kill(process_stop, Some(grace_period)).await?;
+            if escalated {
+                tracing::warn!(
+                    process = %process_stop,
+                    "process did not exit within {grace_period:?} after the signal, killed"
+                );
+            } else {
+                tracing::info!(process = %process_stop, "process stopped, it {outcome}");
             }
+            ctx.record_outcome(step_spec, process_stop, outcome)?;
+            return check_outcome(step_spec, "process(stopped)", process_stop, outcome);
         }
         if let Some(process_wait) = &step_spec.process_wait {
-            let exit_code = ctx
-                .wait(
+            let (outcome, timed_out) = ctx.wait_or_kill(process_wait, timeout).await?;
+            if timed_out {
+                ctx.record_outcome(step_spec, process_wait, ProcessOutcome::TimedOut)?;
+                let message = format!(
+                    "playbook: process {} timed out after {:?}, killed",
                     process_wait,
-                    &step_spec.stdout_file,
-                    &step_spec.stderr_file,
-                    &step_spec.exit_code,
-                )
-                .unwrap();
-            if let Some(check_code) = step_spec.check_exit_code {
-                if check_code != exit_code {
-                    return Err(format!(
-                        "playbook: process {} exits with {}, but expect {}",
-                        process_wait, exit_code, check_code
-                    )
-                    .into());
-                } else {
+                    timeout.unwrap()
+                );
+                if step_spec.allow_failure == Some(true) {
+                    tracing::warn!("{message}, ignored with `allow_failure`");
                     return Ok(());
                 }
+                return Err(message.into());
             }
-            return Ok(());
+            ctx.record_outcome(step_spec, process_wait, outcome)?;
+            return check_outcome(step_spec, "process", process_wait, outcome);
         }
         if let Some(send_variable_name) = &step_spec.send_variable {
             let file = step_spec.file.as_ref().unwrap();
@@ -423,11 +1323,16 @@ impl Context {
         }
         if let Some(recv_variable_name) = &step_spec.recv_variable {
             let recv_variable_name = ctx.render_template(recv_variable_name)?;
-            ctx.recv_variable(
-                &recv_variable_name,
-                &step_spec.file,
-                step_spec.from_role.as_ref().unwrap(),
-                step_spec.role_index.unwrap() as usize,
+            let step_label = ctx.step_label(step_spec);
+            with_timeout(
+                timeout,
+                ctx.recv_variable(
+                    &recv_variable_name,
+                    &step_spec.file,
+                    step_spec.from_role.as_ref().unwrap(),
+                    step_spec.role_index.unwrap() as usize,
+                ),
+                || format!("step {step_label} waiting for variable {recv_variable_name}"),
             )
             .await?;
             return Ok(());
@@ -447,7 +1352,13 @@ impl Context {
         if let Some(read_or_wait_entry) = &step_spec.read_or_wait_entry {
             let file = step_spec.file.as_ref().unwrap();
             let read_or_wait_entry = ctx.render_template(read_or_wait_entry)?;
-            ctx.read_or_wait_entry(&read_or_wait_entry, file).await?;
+            let step_label = ctx.step_label(step_spec);
+            with_timeout(
+                timeout,
+                ctx.read_or_wait_entry(&read_or_wait_entry, file),
+                || format!("step {step_label} waiting for entry {read_or_wait_entry}"),
+            )
+            .await?;
             return Ok(());
         }
         if let Some(update_entry) = &step_spec.update_entry {
@@ -465,17 +1376,418 @@ impl Context {
     }
 }
 
+impl<B: Backend> Drop for Context<B> {
+    fn drop(&mut self) {
+        // the task was cancelled or panicked before its own cleanup, the
+        // processes are waited on a thread of their own so that the runtime
+        // is not blocked for the grace period
+        let (running, deadline) = self.signal_terminate();
+        if running.is_empty() {
+            return;
+        }
+        let thread = std::thread::spawn(move || {
+            for mut terminating in running {
+                let killed = loop {
+                    match reap_terminated(&mut terminating, deadline) {
+                        Some(killed) => break killed,
+                        None => std::thread::sleep(PROCESS_POLL_INTERVAL),
+                    }
+                };
+                log_terminated(&terminating, killed);
+            }
+        });
+        let mut threads = TERMINATING_THREADS.lock().unwrap();
+        threads.retain(|thread| !thread.is_finished());
+        threads.push(thread);
+    }
+}
+
+// threads terminating the processes of the tasks dropped before their end
+static TERMINATING_THREADS: Mutex<Vec<std::thread::JoinHandle<()>>> = Mutex::new(Vec::new());
+
+/// Wait until the processes of the tasks dropped before their end, e.g.
+/// cancelled, are terminated.
+pub fn wait_terminated() {
+    let threads = std::mem::take(&mut *TERMINATING_THREADS.lock().unwrap());
+    for thread in threads {
+        let _ = thread.join();
+    }
+}
+
 pub struct Interpreter {
+    protocol_name: String,
+    protocol_env: BTreeMap<String, String>,
     role: RoleSpec,
     working_dir: String,
+    sandbox_policy: SandboxPolicy,
 }
 
 impl Interpreter {
-    pub fn new(role: RoleSpec, working_dir: &str) -> Interpreter {
+    pub fn new(
+        protocol_spec: &ProtocolSpec,
+        role: RoleSpec,
+        sandbox_policy: SandboxPolicy,
+    ) -> Interpreter {
         Interpreter {
+            protocol_name: protocol_spec.protocol_name.clone(),
+            protocol_env: protocol_spec.env.clone(),
             role,
-            working_dir: working_dir.to_string(),
+            working_dir: protocol_spec.workdir.clone(),
+            sandbox_policy,
+        }
+    }
+
+    /// Render every step of this role for the given task the way `start`
+    /// would, without running processes or touching CoLink. Returns the plan
+    /// and the number of steps that cannot be rendered.
+    pub fn dry_run(
+        &self,
+        user_id: &str,
+        task_id: &str,
+        participants: &[Participant],
+    ) -> Result<(Vec<String>, usize), Box<dyn std::error::Error + Send + Sync + 'static>> {
+        let render = |s: &str| render_template(s, user_id, task_id);
+        let render_path = |s: &str| replace_env_var(&render(s)?);
+        let participants_of = |role: &str, index: Option<i64>| -> String {
+            let matched: Vec<&Participant> = participants
+                .iter()
+                .filter(|participant| participant.role == role)
+                .collect();
+            let matched = match index {
+                Some(index) => match matched.get(index as usize) {
+                    Some(participant) => vec![*participant],
+                    None => return format!("{role}[{index}] (no such participant)"),
+                },
+                None => matched,
+            };
+            let user_ids: Vec<&str> = matched
+                .iter()
+                .map(|participant| participant.user_id.as_str())
+                .collect();
+            format!("{role} [{}]", user_ids.join(", "))
+        };
+
+        let shell_argv =
+            |step: &StepSpec,
+             command: &str|
+             -> Result<String, Box<dyn std::error::Error + Send + Sync + 'static>> {
+                let shell = step.shell.as_ref().or(self.role.shell.as_ref());
+                Ok(format_argv(&shell_argv(
+                    shell.map(String::as_str),
+                    &render(command)?,
+                )?))
+            };
+
+        let secrets = self.role.secrets.clone().unwrap_or_default();
+
+        let mut plan = vec![format!(
+            "workdir: {}",
+            render_path(&role_working_dir(&self.role, &self.working_dir))?
+        )];
+        if self.role.env_clear == Some(true) {
+            let env_allow = self.role.env_allow.clone().unwrap_or_default();
+            plan.push(format!(
+                "environment of the operator cleared, except: [{}]",
+                env_allow.join(", ")
+            ));
+        }
+        if self.role.pass_jwt == Some(true) {
+            plan.push("COLINK_JWT passed to the processes".to_string());
+        }
+        let mut errors = 0;
+        for (i, step) in self.role.steps.iter().enumerate() {
+            plan.push(format!("steps[{i}]:"));
+            // a step that cannot be rendered is reported, and the next ones
+            // are still shown
+            let mut render_step =
+                || -> Result<(), Box<dyn std::error::Error + Send + Sync + 'static>> {
+                    if let Some(if_command) = &step._if {
+                        plan.push(format!("  if: {}", shell_argv(step, if_command)?));
+                    }
+                    if let Some(process) = &step.process {
+                        plan.push(format!(
+                            "  process `{}`: {}",
+                            step.step_name.as_deref().unwrap_or_default(),
+                            shell_argv(step, process)?
+                        ));
+                    }
+                    if let Some(argv) = &step.argv {
+                        let argv = argv
+                            .iter()
+                            .map(|arg| render(arg))
+                            .collect::<Result<Vec<String>, _>>()?;
+                        plan.push(format!(
+                            "  process `{}`: {} (no shell)",
+                            step.step_name.as_deref().unwrap_or_default(),
+                            format_argv(&argv)
+                        ));
+                    }
+                    if step.starts_process() || step._if.is_some() {
+                        let limits = match &step.limits {
+                            Some(limits) => limits.or(&self.role.limits),
+                            None => self.role.limits.clone(),
+                        };
+                        let limits: Vec<String> = [
+                            ("cpu_time", limits.cpu_time),
+                            ("address_space", limits.address_space),
+                            ("open_files", limits.open_files.map(|n| n.to_string())),
+                            ("nice", limits.nice.map(|n| n.to_string())),
+                            ("memory_max", limits.memory_max),
+                            ("cpu_max", limits.cpu_max),
+                        ]
+                        .into_iter()
+                        .filter_map(|(name, value)| Some(format!("{name}={}", value?)))
+                        .collect();
+                        if !limits.is_empty() {
+                            plan.push(format!("    limits: {}", limits.join(", ")));
+                        }
+                    }
+                    if step.starts_process() || step._if.is_some() {
+                        match step_sandbox(self.sandbox_policy, &self.role, step) {
+                            Some(true) => plan.push("    sandbox with network".to_string()),
+                            Some(false) => plan.push("    sandbox".to_string()),
+                            None => {}
+                        }
+                    }
+                    if let Some(stdin_file) = &step.stdin_file {
+                        plan.push(format!("    stdin from {}", render_path(stdin_file)?));
+                    }
+                    if let Some(stdin) = &step.stdin {
+                        plan.push(format!("    stdin: {:?}", render(stdin)?));
+                    }
+                    if step.starts_process() || step._if.is_some() {
+                        let mut env = self.protocol_env.clone();
+                        env.extend(self.role.env.clone());
+                        env.extend(step.env.clone().unwrap_or_default());
+                        for (name, value) in env {
+                            let value = if secrets.contains(&name) {
+                                REDACTED.to_string()
+                            } else {
+                                render(&value)?
+                            };
+                            plan.push(format!("    env {name}={value:?}"));
+                        }
+                    }
+                    if let Some(process_kill) = &step.process_kill {
+                        let signal = step.signal.as_deref().unwrap_or("SIGKILL");
+                        let mut line = format!("  process_kill: `{process_kill}` with {signal}");
+                        if parse_signal(signal) != Ok(libc::SIGKILL) {
+                            line += &format!(
+                                ", killed after {}",
+                                step.grace_period
+                                    .clone()
+                                    .or(self.role.grace_period.clone())
+                                    .unwrap_or(format!("{DEFAULT_GRACE_PERIOD:?}"))
+                            );
+                        }
+                        plan.push(line);
+                    }
+                    if let Some(process_stop) = &step.process_stop {
+                        plan.push(format!(
+                            "  process_stop: `{process_stop}` with {}, killed after {}",
+                            step.signal.as_deref().unwrap_or("SIGTERM"),
+                            step.grace_period
+                                .clone()
+                                .or(self.role.grace_period.clone())
+                                .unwrap_or(format!("{DEFAULT_GRACE_PERIOD:?}"))
+                        ));
+                    }
+                    if let Some(process_wait) = &step.process_wait {
+                        plan.push(format!("  process_wait: `{process_wait}`"));
+                    }
+                    if step.starts_process()
+                        || step.process_kill.is_some()
+                        || step.process_stop.is_some()
+                        || step.process_wait.is_some()
+                    {
+                        for (name, file) in [
+                            ("stdout_file", &step.stdout_file),
+                            ("stderr_file", &step.stderr_file),
+                            ("exit_code", &step.exit_code),
+                        ] {
+                            if let Some(file) = file {
+                                plan.push(format!("    {name}: {}", render_path(file)?));
+                            }
+                        }
+                        if let Some(format) = step.exit_code_format {
+                            if format == ExitCodeFormat::Json {
+                                plan.push("    exit_code_format: json".to_string());
+                            }
+                        }
+                        if let Some(check_exit_code) = &step.check_exit_code {
+                            plan.push(format!("    check_exit_code: {check_exit_code}"));
+                        }
+                        if let Some(check_signal) = &step.check_signal {
+                            plan.push(format!("    check_signal: {check_signal}"));
+                        }
+                        if step.expect_success == Some(true) {
+                            plan.push("    expect_success".to_string());
+                        }
+                        if step.allow_failure == Some(true) {
+                            plan.push("    allow_failure".to_string());
+                        }
+                    }
+                    if let Some(send_variable) = &step.send_variable {
+                        plan.push(format!(
+                            "  send_variable `{}` from {} to {}",
+                            render(send_variable)?,
+                            render_path(step.file.as_deref().unwrap_or_default())?,
+                            participants_of(
+                                step.to_role.as_deref().unwrap_or_default(),
+                                step.role_index
+                            )
+                        ));
+                    }
+                    if let Some(recv_variable) = &step.recv_variable {
+                        let to_file = match &step.file {
+                            Some(file) => format!(" into {}", render_path(file)?),
+                            None => String::new(),
+                        };
+                        plan.push(format!(
+                            "  recv_variable `{}` from {}{to_file}",
+                            render(recv_variable)?,
+                            participants_of(
+                                step.from_role.as_deref().unwrap_or_default(),
+                                Some(step.role_index.unwrap_or_default())
+                            )
+                        ));
+                    }
+                    for (action, entry, direction) in [
+                        ("create_entry", &step.create_entry, "from"),
+                        ("update_entry", &step.update_entry, "from"),
+                        ("read_entry", &step.read_entry, "into"),
+                        ("read_or_wait_entry", &step.read_or_wait_entry, "into"),
+                    ] {
+                        if let Some(entry) = entry {
+                            plan.push(format!(
+                                "  {action} `{}` {direction} {}",
+                                render(entry)?,
+                                render_path(step.file.as_deref().unwrap_or_default())?
+                            ));
+                        }
+                    }
+                    if let Some(delete_entry) = &step.delete_entry {
+                        plan.push(format!("  delete_entry `{}`", render(delete_entry)?));
+                    }
+                    if let Some(timeout) = &step.timeout {
+                        plan.push(format!("  timeout: {timeout}"));
+                    }
+                    if let Some(retry) = &step.retry {
+                        plan.push(format!("  retry: up to {} attempts", retry.max_attempts));
+                    }
+                    Ok(())
+                };
+            if let Err(e) = render_step() {
+                plan.push(format!("  error: {e}"));
+                errors += 1;
+            }
         }
+        Ok((plan, errors))
+    }
+}
+
+impl Interpreter {
+    /// Run the steps of this role as one participant of a task.
+    pub async fn run<B: Backend>(
+        &self,
+        cl: B,
+        param: Vec<u8>,
+        participants: Vec<Participant>,
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync + 'static>> {
+        let mut ctx = Context::new(self, &participants, &param, cl);
+        let task_span = tracing::info_span!(
+            "task",
+            protocol = %self.protocol_name,
+            role = %self.role.name,
+            task_id = %ctx.cl.get_task_id()?,
+            user_id = %ctx.cl.get_user_id()?,
+        );
+        let start = Instant::now();
+        METRICS.add(TASKS_STARTED, &ctx.metric_labels(), 1);
+        ctx.publish_status().instrument(task_span.clone()).await;
+        let res: Result<(), Box<dyn std::error::Error + Send + Sync + 'static>> = async {
+            ctx.check_roles_num()?;
+            let rendered_path = ctx.render_template(&ctx.working_dir)?;
+            let set_dir = std::path::absolute(replace_env_var(&rendered_path)?)?;
+            std::fs::create_dir_all(&set_dir)?;
+            ctx.working_dir = set_dir.to_string_lossy().to_string();
+            ctx.store_param_to_file()?;
+            for (index, step) in self.role.steps.iter().enumerate() {
+                Self::evaluate_traced(&mut ctx, index, step).await?;
+                ctx.step_counter += 1;
+            }
+            Ok::<(), Box<dyn std::error::Error + Send + Sync + 'static>>(())
+        }
+        .instrument(task_span.clone())
+        .await
+        .map_err(|e| ctx.redactor.redact(&e.to_string()).into());
+        ctx.status.terminated_processes = ctx
+            .terminate_processes()
+            .instrument(task_span.clone())
+            .await;
+        ctx.status.finished_at = Some(unix_millis());
+        match &res {
+            Ok(()) => {
+                ctx.status.state = TaskState::Succeeded;
+                METRICS.add(TASKS_COMPLETED, &ctx.metric_labels(), 1);
+            }
+            Err(e) => {
+                ctx.status.state = TaskState::Failed;
+                ctx.status.error = Some(e.to_string());
+                METRICS.add(TASKS_FAILED, &ctx.metric_labels(), 1);
+            }
+        }
+        ctx.publish_status().instrument(task_span.clone()).await;
+        let duration_ms = start.elapsed().as_millis() as u64;
+        task_span.in_scope(|| match &res {
+            Ok(()) => tracing::info!(duration_ms, outcome = "succeeded", "task succeeded"),
+            Err(e) => {
+                tracing::error!(duration_ms, outcome = "failed", error = %e, "task failed")
+            }
+        });
+        res
+    }
+
+    // Evaluate one step in its own span and log how it ended.
+    async fn evaluate_traced<B: Backend>(
+        ctx: &mut Context<B>,
+        index: usize,
+        step: &StepSpec,
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync + 'static>> {
+        let step_span = tracing::info_span!(
+            "step",
+            index,
+            step_name = step.step_name.as_deref().unwrap_or(""),
+            action = step.action(),
+        );
+        step_span.in_scope(|| tracing::info!("step started"));
+        ctx.status.step = Some(StepStatus {
+            index,
+            step_name: step.step_name.clone(),
+            action: step.action(),
+            started_at: unix_millis(),
+        });
+        ctx.publish_status().instrument(step_span.clone()).await;
+        let start = Instant::now();
+        let res = Context::evaluate(ctx, step)
+            .instrument(step_span.clone())
+            .await
+            .map_err(|e| ctx.redactor.redact(&e.to_string()).into());
+        let [protocol, role] = ctx.metric_labels();
+        METRICS.observe(
+            STEP_DURATION,
+            &[protocol, role, ("action", step.action())],
+            start.elapsed().as_secs_f64(),
+        );
+        let duration_ms = start.elapsed().as_millis() as u64;
+        step_span.in_scope(|| match &res {
+            Ok(true) => tracing::info!(duration_ms, outcome = "succeeded", "step succeeded"),
+            Ok(false) => tracing::info!(duration_ms, outcome = "skipped", "step skipped by `if`"),
+            Err(e) => {
+                tracing::error!(duration_ms, outcome = "failed", error = %e, "step failed")
+            }
+        });
+        res.map(|_| ())
     }
 }
 
@@ -487,23 +1799,654 @@ impl ProtocolEntry for Interpreter {
         param: Vec<u8>,
         participants: Vec<Participant>,
     ) -> Result<(), Box<dyn std::error::Error + Send + Sync + 'static>> {
-        let mut ctx = Context::new(
-            self.role.clone(),
-            &self.working_dir,
-            &participants,
-            &param,
-            cl,
-        );
-        ctx.check_roles_num()?;
-        let rendered_path = ctx.render_template(&ctx.working_dir).unwrap();
-        let set_dir = Context::replace_env_var(&rendered_path).unwrap();
-        std::fs::create_dir_all(&set_dir)?;
-        std::env::set_current_dir(set_dir)?;
-        ctx.store_param_to_file()?;
-        for step in &self.role.steps {
-            Context::evaluate(&mut ctx, step).await?;
-            ctx.step_counter += 1;
+        self.run(cl, param, participants).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::backend::tests::{
+        participant, protocol, run, test_dir, unchecked_protocol, TASK_ID,
+    };
+    use crate::backend::{Backend, MemoryBackend, MemoryStorage};
+    use crate::redact::REDACTED;
+    use crate::spec_parser::ProtocolSpec;
+    use crate::status::status_entry_key;
+    use std::{path::Path, sync::Arc};
+
+    type Error = Box<dyn std::error::Error + Send + Sync + 'static>;
+
+    // Run role `a` of the protocol as its only participant `a0`, and return
+    // the task status it published.
+    async fn run_with_status(
+        protocol_spec: &ProtocolSpec,
+    ) -> (Result<(), Error>, serde_json::Value) {
+        let participants = [participant("a0", "a")];
+        let storage = Arc::new(MemoryStorage::default());
+        let res = run(protocol_spec, &storage, &participants[0], &participants).await;
+        let status = MemoryBackend::new(storage, "a0", TASK_ID)
+            .read_entry(&status_entry_key(TASK_ID, "a"))
+            .await
+            .unwrap();
+        (res, serde_json::from_slice(&status).unwrap())
+    }
+
+    async fn run_alone(protocol_spec: &ProtocolSpec) -> Result<(), Error> {
+        run_with_status(protocol_spec).await.0
+    }
+
+    // A command failing while the process whose pid is in the file `pid`
+    // runs, a zombie does not.
+    const CHECK_PID_STOPPED: &str = r#"case "$(cat /proc/$(cat pid)/stat 2>/dev/null)" in "" | *") Z "*) exit 0 ;; *) exit 1 ;; esac"#;
+
+    // Whether the process `pid` written in `file` runs, a zombie does not.
+    fn is_running(file: &Path) -> bool {
+        let pid = std::fs::read_to_string(file).unwrap();
+        match std::fs::read_to_string(format!("/proc/{}/stat", pid.trim())) {
+            Ok(stat) => !stat.rsplit_once(") ").unwrap().1.starts_with('Z'),
+            Err(_) => false,
         }
-        Ok(())
+    }
+
+    #[tokio::test]
+    async fn wait_process_not_running() {
+        let dir = test_dir("wait-not-running");
+        let protocol_spec = protocol(
+            &dir,
+            r#"
+[[t.roles.a.playbook.steps]]
+step_name = "p"
+process = "true"
+[[t.roles.a.playbook.steps]]
+if = "true"
+process_wait = "p"
+[[t.roles.a.playbook.steps]]
+process_wait = "p"
+"#,
+        );
+        let e = run_alone(&protocol_spec).await.unwrap_err();
+        assert_eq!(e.to_string(), "playbook: process p is not running");
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn kill_process_not_running() {
+        let dir = test_dir("kill-not-running");
+        let protocol_spec = protocol(
+            &dir,
+            r#"
+[[t.roles.a.playbook.steps]]
+step_name = "p"
+process = "sleep 30"
+[[t.roles.a.playbook.steps]]
+if = "true"
+process_kill = "p"
+check_signal = "SIGKILL"
+[[t.roles.a.playbook.steps]]
+process_kill = "p"
+"#,
+        );
+        let e = run_alone(&protocol_spec).await.unwrap_err();
+        assert_eq!(e.to_string(), "playbook: process p is not running");
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn send_missing_file() {
+        let dir = test_dir("send-missing-file");
+        let protocol_spec = protocol(
+            &dir,
+            r#"
+[[t.roles.a.playbook.steps]]
+send_variable = "x"
+file = "missing"
+to_role = "b"
+[[t.roles.b.playbook.steps]]
+recv_variable = "x"
+from_role = "a"
+index = 0
+"#,
+        );
+        let e = run_alone(&protocol_spec).await.unwrap_err();
+        let path = dir.join("a0/missing");
+        assert_eq!(
+            e.to_string(),
+            format!(
+                "playbook: failed to open {}: No such file or directory (os error 2)",
+                path.display()
+            )
+        );
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn start_process_already_running() {
+        let dir = test_dir("already-running");
+        let protocol_spec = unchecked_protocol(
+            &dir,
+            r#"
+[[t.roles.a.playbook.steps]]
+step_name = "s"
+process = "sleep 41"
+[[t.roles.a.playbook.steps]]
+step_name = "s"
+process = "sleep 42"
+"#,
+        );
+        let (res, status) = run_with_status(&protocol_spec).await;
+        assert_eq!(
+            res.unwrap_err().to_string(),
+            "playbook: process s is already running"
+        );
+        assert_eq!(status["terminated_processes"], serde_json::json!(["s"]));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn terminate_processes_left_by_joined_process() {
+        let dir = test_dir("joined-leftover");
+        let protocol_spec = protocol(
+            &dir,
+            r#"
+[t.roles.a.playbook]
+grace_period = "200ms"
+[[t.roles.a.playbook.steps]]
+step_name = "p"
+process = "sleep 30 & echo $! > pid"
+process_wait = "p"
+"#,
+        );
+        let (res, status) = run_with_status(&protocol_spec).await;
+        res.unwrap();
+        assert_eq!(status["terminated_processes"], serde_json::json!(["p"]));
+        assert!(!is_running(&dir.join("a0/pid")));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn retry_until_success() {
+        let dir = test_dir("retry-success");
+        let protocol_spec = protocol(
+            &dir,
+            r#"
+[[t.roles.a.playbook.steps]]
+step_name = "p"
+process = "echo attempt >> attempts; test $(wc -l < attempts) -ge 3"
+process_wait = "p"
+expect_success = true
+retry = { max_attempts = 3, delay = "10ms" }
+"#,
+        );
+        run_alone(&protocol_spec).await.unwrap();
+        let attempts = std::fs::read_to_string(dir.join("a0/attempts")).unwrap();
+        assert_eq!(attempts.lines().count(), 3);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn retry_gives_up() {
+        let dir = test_dir("retry-gives-up");
+        let protocol_spec = protocol(
+            &dir,
+            r#"
+[[t.roles.a.playbook.steps]]
+step_name = "p"
+process = "exit 75"
+process_wait = "p"
+check_exit_code = 0
+retry = { max_attempts = 2, delay = "10ms", exit_codes = [75] }
+"#,
+        );
+        let e = run_alone(&protocol_spec).await.unwrap_err();
+        assert_eq!(
+            e.to_string(),
+            "playbook: process p exits with 75, but expect 0 (failed after 2 attempts)"
+        );
+        let protocol_spec = protocol(
+            &dir,
+            r#"
+[[t.roles.a.playbook.steps]]
+step_name = "q"
+process = "echo attempt >> attempts; exit 3"
+process_wait = "q"
+check_exit_code = 0
+retry = { max_attempts = 3, delay = "10ms", exit_codes = [75] }
+"#,
+        );
+        // not one of `exit_codes`
+        let e = run_alone(&protocol_spec).await.unwrap_err();
+        assert_eq!(
+            e.to_string(),
+            "playbook: process q exits with 3, but expect 0"
+        );
+        let attempts = std::fs::read_to_string(dir.join("a0/attempts")).unwrap();
+        assert_eq!(attempts.lines().count(), 1);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn retry_exponential_backoff() {
+        let dir = test_dir("retry-backoff");
+        let protocol_spec = protocol(
+            &dir,
+            r#"
+[[t.roles.a.playbook.steps]]
+step_name = "p"
+process = "false"
+process_wait = "p"
+expect_success = true
+retry = { max_attempts = 4, backoff = "exponential", delay = "100ms", max_delay = "150ms" }
+"#,
+        );
+        let start = std::time::Instant::now();
+        run_alone(&protocol_spec).await.unwrap_err();
+        // 100ms, then 200ms capped to 150ms twice
+        let elapsed = start.elapsed();
+        assert!(
+            elapsed >= std::time::Duration::from_millis(400),
+            "{elapsed:?}"
+        );
+        assert!(
+            elapsed < std::time::Duration::from_millis(1000),
+            "{elapsed:?}"
+        );
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn retry_colink_action() {
+        let dir = test_dir("retry-colink");
+        let protocol_spec = protocol(
+            &dir,
+            r#"
+[[t.roles.a.playbook.steps]]
+read_entry = "e"
+file = "e"
+retry = { max_attempts = 2, delay = "200ms", on = ["colink"] }
+"#,
+        );
+        let participants = [participant("a0", "a")];
+        let storage = Arc::new(MemoryStorage::default());
+        let writer = MemoryBackend::new(storage.clone(), "a0", TASK_ID);
+        let (res, _) = tokio::join!(
+            run(&protocol_spec, &storage, &participants[0], &participants),
+            async {
+                tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+                writer.create_entry("e", b"second attempt").await.unwrap();
+            },
+        );
+        res.unwrap();
+        assert_eq!(
+            std::fs::read_to_string(dir.join("a0/e")).unwrap(),
+            "second attempt"
+        );
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn retry_waiting_another_process() {
+        let dir = test_dir("retry-other");
+        let protocol_spec = unchecked_protocol(
+            &dir,
+            r#"
+[[t.roles.a.playbook.steps]]
+step_name = "other"
+process = "true"
+process_wait = "other"
+[[t.roles.a.playbook.steps]]
+step_name = "s"
+process = "sleep 30"
+process_wait = "other"
+retry = { max_attempts = 3, delay = "10ms" }
+"#,
+        );
+        let (res, status) = run_with_status(&protocol_spec).await;
+        assert_eq!(
+            res.unwrap_err().to_string(),
+            "playbook: process other is not running"
+        );
+        assert_eq!(status["terminated_processes"], serde_json::json!(["s"]));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn killed_process_matches_signal_number() {
+        let dir = test_dir("kill-exit-code");
+        let protocol_spec = protocol(
+            &dir,
+            r#"
+[[t.roles.a.playbook.steps]]
+step_name = "p"
+process = "sleep 30"
+[[t.roles.a.playbook.steps]]
+process_kill = "p"
+check_exit_code = 9
+exit_code = "code"
+"#,
+        );
+        run_alone(&protocol_spec).await.unwrap();
+        assert_eq!(std::fs::read_to_string(dir.join("a0/code")).unwrap(), "9");
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn process_outcomes() {
+        let dir = test_dir("outcomes");
+        let protocol_spec = protocol(
+            &dir,
+            r#"
+[[t.roles.a.playbook.steps]]
+step_name = "exits"
+process = "exit 3"
+process_wait = "exits"
+check_exit_code = "2-5"
+exit_code = "exits.json"
+exit_code_format = "json"
+[[t.roles.a.playbook.steps]]
+step_name = "signaled"
+process = "sleep 30"
+[[t.roles.a.playbook.steps]]
+process_kill = "signaled"
+signal = "SIGTERM"
+check_signal = "SIGTERM"
+exit_code = "signaled.json"
+exit_code_format = "json"
+[[t.roles.a.playbook.steps]]
+step_name = "times_out"
+process = "sleep 30"
+process_wait = "times_out"
+timeout = "100ms"
+allow_failure = true
+exit_code = "times_out.json"
+exit_code_format = "json"
+[[t.roles.a.playbook.steps]]
+step_name = "fails"
+process = "exit 1"
+process_wait = "fails"
+expect_success = true
+exit_code = "fails"
+"#,
+        );
+        let (res, status) = run_with_status(&protocol_spec).await;
+        assert_eq!(
+            res.unwrap_err().to_string(),
+            "playbook: process fails exits with 1, but expect success"
+        );
+        let read = |file: &str| std::fs::read_to_string(dir.join("a0").join(file)).unwrap();
+        assert_eq!(read("exits.json"), r#"{"code":3,"outcome":"exited"}"#);
+        assert_eq!(
+            read("signaled.json"),
+            r#"{"outcome":"signaled","signal":15,"signal_name":"SIGTERM"}"#
+        );
+        assert_eq!(read("times_out.json"), r#"{"outcome":"timed_out"}"#);
+        assert_eq!(read("fails"), "1");
+        assert_eq!(
+            status["process_outcomes"],
+            serde_json::json!({
+                "exits": {"outcome": "exited", "code": 3},
+                "signaled": {"outcome": "signaled", "signal": 15, "signal_name": "SIGTERM"},
+                "times_out": {"outcome": "timed_out"},
+                "fails": {"outcome": "exited", "code": 1},
+            })
+        );
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn kill_process_group() {
+        let dir = test_dir("kill-group");
+        let protocol_spec = protocol(
+            &dir,
+            &format!(
+                r#"
+[[t.roles.a.playbook.steps]]
+step_name = "p"
+process = "sleep 30 & echo $! > pid; wait"
+[[t.roles.a.playbook.steps]]
+step_name = "started"
+process = "while [ ! -s pid ]; do sleep 0.01; done"
+process_wait = "started"
+[[t.roles.a.playbook.steps]]
+process_kill = "p"
+check_signal = "SIGKILL"
+[[t.roles.a.playbook.steps]]
+step_name = "check"
+process = '{CHECK_PID_STOPPED}'
+process_wait = "check"
+expect_success = true
+"#
+            ),
+        );
+        run_alone(&protocol_spec).await.unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn kill_after_grace_period() {
+        let dir = test_dir("kill-grace-period");
+        let protocol_spec = protocol(
+            &dir,
+            r#"
+[[t.roles.a.playbook.steps]]
+step_name = "p"
+process = "trap '' TERM; echo > ready; sleep 30"
+[[t.roles.a.playbook.steps]]
+step_name = "started"
+process = "while [ ! -s ready ]; do sleep 0.01; done"
+process_wait = "started"
+[[t.roles.a.playbook.steps]]
+process_kill = "p"
+signal = "SIGTERM"
+grace_period = "200ms"
+check_signal = "SIGKILL"
+"#,
+        );
+        let start = std::time::Instant::now();
+        run_alone(&protocol_spec).await.unwrap();
+        assert!(start.elapsed() >= std::time::Duration::from_millis(200));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn stop_process() {
+        let dir = test_dir("stop");
+        let protocol_spec = protocol(
+            &dir,
+            r#"
+[t.roles.a.playbook]
+grace_period = "200ms"
+[[t.roles.a.playbook.steps]]
+step_name = "p"
+process = "trap 'exit 7' TERM; echo > ready; sleep 30 & wait"
+[[t.roles.a.playbook.steps]]
+step_name = "started"
+process = "while [ ! -s ready ]; do sleep 0.01; done"
+process_wait = "started"
+[[t.roles.a.playbook.steps]]
+process_stop = "p"
+check_exit_code = 7
+"#,
+        );
+        run_alone(&protocol_spec).await.unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn stop_process_after_role_grace_period() {
+        let dir = test_dir("stop-grace-period");
+        let protocol_spec = protocol(
+            &dir,
+            r#"
+[t.roles.a.playbook]
+grace_period = "300ms"
+[[t.roles.a.playbook.steps]]
+step_name = "p"
+process = "trap '' TERM; echo > ready; sleep 30"
+[[t.roles.a.playbook.steps]]
+step_name = "started"
+process = "while [ ! -s ready ]; do sleep 0.01; done"
+process_wait = "started"
+[[t.roles.a.playbook.steps]]
+process_stop = "p"
+check_signal = "SIGKILL"
+"#,
+        );
+        let start = std::time::Instant::now();
+        run_alone(&protocol_spec).await.unwrap();
+        assert!(start.elapsed() >= std::time::Duration::from_millis(300));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn argv_and_shell() {
+        let dir = test_dir("argv-shell");
+        let protocol_spec = protocol(
+            &dir,
+            r#"
+[[t.roles.a.playbook.steps]]
+step_name = "argv"
+argv = ["sh", "-c", "printf '%s\n' \"$1\" > argv", "sh", "an $ARG with spaces"]
+process_wait = "argv"
+expect_success = true
+[[t.roles.a.playbook.steps]]
+step_name = "sh"
+shell = "sh"
+process = "echo $0 > shell"
+process_wait = "sh"
+expect_success = true
+[[t.roles.a.playbook.steps]]
+step_name = "none"
+shell = "none"
+process = "touch none"
+process_wait = "none"
+expect_success = true
+"#,
+        );
+        run_alone(&protocol_spec).await.unwrap();
+        let workdir = dir.join("a0");
+        let read = |file| std::fs::read_to_string(workdir.join(file)).unwrap();
+        assert_eq!(read("argv"), "an $ARG with spaces\n");
+        assert_eq!(read("shell"), "sh\n");
+        assert!(workdir.join("none").exists());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn process_env() {
+        let dir = test_dir("env");
+        let protocol_spec = protocol(
+            &dir,
+            r#"
+[t.env]
+A = "protocol"
+B = "protocol"
+C = "protocol"
+[t.roles.a.playbook.env]
+B = "role"
+C = "role"
+[[t.roles.a.playbook.steps]]
+step_name = "env"
+process = "env | grep -E '^([ABCD]|COLINK_[A-Z_]+)=' | LC_ALL=C sort > env"
+env = { C = "step", D = "{{user_id}}/{{task_id}}" }
+process_wait = "env"
+expect_success = true
+"#,
+        );
+        let participants = [participant("a0", "a"), participant("a1", "a")];
+        let storage = Arc::new(MemoryStorage::default());
+        run(&protocol_spec, &storage, &participants[1], &participants)
+            .await
+            .unwrap();
+        let env = std::fs::read_to_string(dir.join("a1/env")).unwrap();
+        let param_file = dir.join("a1/param.json");
+        assert_eq!(
+            env,
+            format!(
+                "A=protocol\nB=role\nC=step\nCOLINK_PARAM_FILE={}\nCOLINK_PARTICIPANT_INDEX=1\n\
+                 COLINK_ROLE=a\nCOLINK_TASK_ID={TASK_ID}\nCOLINK_USER_ID=a1\nD=a1/{TASK_ID}\n",
+                param_file.display()
+            )
+        );
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn env_clear_and_secrets() {
+        let dir = test_dir("env-clear");
+        let protocol_spec = protocol(
+            &dir,
+            r#"
+[t.roles.a.playbook]
+env_clear = true
+env_allow = ["PAT*"]
+secrets = ["TOKEN"]
+env = { TOKEN = "hidden-{{user_id}}" }
+[[t.roles.a.playbook.steps]]
+step_name = "env"
+argv = ["env"]
+stdout_file = "env"
+process_wait = "env"
+expect_success = true
+[[t.roles.a.playbook.steps]]
+send_variable = "x"
+file = "hidden-a0"
+to_role = "a"
+"#,
+        );
+        let (res, status) = run_with_status(&protocol_spec).await;
+        let mut names: Vec<String> = std::fs::read_to_string(dir.join("a0/env"))
+            .unwrap()
+            .lines()
+            .map(|line| line.split('=').next().unwrap().to_string())
+            .filter(|name| !name.starts_with("COLINK_"))
+            .collect();
+        names.sort();
+        let expected = match std::env::var_os("PATH") {
+            Some(_) => vec!["PATH", "TOKEN"],
+            None => vec!["TOKEN"],
+        };
+        assert_eq!(names, expected);
+        let error = format!(
+            "playbook: failed to open {}: No such file or directory (os error 2)",
+            dir.join(format!("a0/{REDACTED}")).display()
+        );
+        assert_eq!(res.unwrap_err().to_string(), error);
+        assert_eq!(status["error"], serde_json::json!(error));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn process_stdin() {
+        let dir = test_dir("stdin");
+        let protocol_spec = protocol(
+            &dir,
+            r#"
+[[t.roles.a.playbook.steps]]
+step_name = "text"
+process = "cat > text; printf 'from a file\n' > input"
+stdin = "some text\n"
+process_wait = "text"
+expect_success = true
+[[t.roles.a.playbook.steps]]
+step_name = "file"
+process = "cat > file"
+stdin_file = "input"
+process_wait = "file"
+expect_success = true
+[[t.roles.a.playbook.steps]]
+step_name = "empty"
+process = "cat > empty"
+process_wait = "empty"
+timeout = "10s"
+expect_success = true
+"#,
+        );
+        run_alone(&protocol_spec).await.unwrap();
+        let workdir = dir.join("a0");
+        let read = |file| std::fs::read_to_string(workdir.join(file)).unwrap();
+        assert_eq!(read("text"), "some text\n");
+        assert_eq!(read("file"), "from a file\n");
+        assert_eq!(read("empty"), "");
+        std::fs::remove_dir_all(&dir).unwrap();
     }
 }
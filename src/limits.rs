@@ -0,0 +1,298 @@
+use crate::spec_parser::{parse_duration, parse_size, LimitsSpec, ProtocolSpec};
+use std::{
+    env, fs, io,
+    os::unix::io::{AsRawFd, RawFd},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        OnceLock,
+    },
+};
+
+type Error = Box<dyn std::error::Error + Send + Sync + 'static>;
+
+// period of `cpu.max` in microseconds, the default of the kernel
+const CPU_MAX_PERIOD: u64 = 100_000;
+
+/// The rlimits and nice level of a process, set between fork and exec.
+#[derive(Clone, Copy, Default)]
+pub struct ResourceLimits {
+    cpu_time: Option<u64>,
+    address_space: Option<u64>,
+    open_files: Option<u64>,
+    nice: Option<i32>,
+}
+
+impl ResourceLimits {
+    pub fn new(spec: &LimitsSpec) -> Result<ResourceLimits, String> {
+        let cpu_time = match &spec.cpu_time {
+            Some(cpu_time) => Some((parse_duration(cpu_time)?.as_secs_f64().ceil() as u64).max(1)),
+            None => None,
+        };
+        let address_space = spec.address_space.as_deref().map(parse_size).transpose()?;
+        let open_files = match spec.open_files {
+            Some(open_files) if open_files < 1 => {
+                return Err(format!(
+                    "`open_files` should be at least 1, found {open_files}"
+                ))
+            }
+            open_files => open_files.map(|open_files| open_files as u64),
+        };
+        let nice = match spec.nice {
+            Some(nice) if !(-20..=19).contains(&nice) => {
+                return Err(format!("`nice` should be from -20 to 19, found {nice}"))
+            }
+            nice => nice.map(|nice| nice as i32),
+        };
+        Ok(ResourceLimits {
+            cpu_time,
+            address_space,
+            open_files,
+            nice,
+        })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.cpu_time.is_none()
+            && self.address_space.is_none()
+            && self.open_files.is_none()
+            && self.nice.is_none()
+    }
+
+    /// Apply the limits to the calling process. This runs in the child after
+    /// fork, so it only makes async-signal-safe calls.
+    pub fn apply(&self) -> io::Result<()> {
+        // SIGXCPU at the limit, then SIGKILL a second later
+        let cpu_time = self.cpu_time.map(|cpu_time| (cpu_time, cpu_time + 1));
+        let address_space = self.address_space.map(|size| (size, size));
+        let open_files = self.open_files.map(|files| (files, files));
+        for (resource, limit) in [
+            (libc::RLIMIT_CPU, cpu_time),
+            (libc::RLIMIT_AS, address_space),
+            (libc::RLIMIT_NOFILE, open_files),
+        ] {
+            if let Some((soft, hard)) = limit {
+                let rlimit = libc::rlimit {
+                    rlim_cur: soft as libc::rlim_t,
+                    rlim_max: hard as libc::rlim_t,
+                };
+                if unsafe { libc::setrlimit(resource, &rlimit) } == -1 {
+                    return Err(io::Error::last_os_error());
+                }
+            }
+        }
+        if let Some(nice) = self.nice {
+            if unsafe { libc::setpriority(libc::PRIO_PROCESS, 0, nice) } == -1 {
+                return Err(io::Error::last_os_error());
+            }
+        }
+        Ok(())
+    }
+}
+
+/// A cgroup v2 made for one process, holding it and everything it starts.
+pub struct Cgroup {
+    pub path: PathBuf,
+    procs: fs::File,
+}
+
+// tells apart the cgroups of a step that runs again, e.g. on retry
+static CGROUP_COUNTER: AtomicU64 = AtomicU64::new(0);
+
+// The cgroup of this process in the cgroup v2 hierarchy.
+fn own_cgroup() -> Result<PathBuf, Error> {
+    // `/sys/fs/cgroup`, or e.g. `/sys/fs/cgroup/unified` next to cgroup v1
+    let mounts = fs::read_to_string("/proc/self/mounts")?;
+    let mount_point = mounts
+        .lines()
+        .map(|line| line.split_whitespace().collect::<Vec<&str>>())
+        .find(|fields| fields.len() > 2 && fields[2] == "cgroup2")
+        .map(|fields| fields[1].to_string())
+        .ok_or("cgroup v2 is not mounted")?;
+    let cgroups = fs::read_to_string("/proc/self/cgroup")?;
+    let path = cgroups
+        .lines()
+        .find_map(|line| line.strip_prefix("0::"))
+        .ok_or("no cgroup v2 hierarchy")?;
+    Ok(Path::new(&mount_point).join(path.trim_start_matches('/')))
+}
+
+// the cgroup the playbook creates its cgroups in, set up by `init_cgroups`
+static PARENT_CGROUP: OnceLock<Result<PathBuf, String>> = OnceLock::new();
+
+/// Set up the cgroup the processes with `memory_max` or `cpu_max` are put
+/// in, with `controllers` (e.g. `memory`) enabled for them. This is done
+/// once, the processes started later fail with the same error.
+pub fn init_cgroups(controllers: &[&str]) -> Result<(), String> {
+    PARENT_CGROUP
+        .get_or_init(|| parent_cgroup(controllers).map_err(|e| e.to_string()))
+        .clone()
+        .map(|_| ())
+}
+
+/// The cgroup controllers the processes of `protocol_specs` need.
+pub fn needed_controllers(protocol_specs: &[ProtocolSpec]) -> Vec<&'static str> {
+    let limits = protocol_specs
+        .iter()
+        .flat_map(|protocol_spec| &protocol_spec.roles)
+        .flat_map(|role_spec| {
+            std::iter::once(&role_spec.limits).chain(
+                role_spec
+                    .steps
+                    .iter()
+                    .filter_map(|step| step.limits.as_ref()),
+            )
+        });
+    let mut controllers = Vec::new();
+    for limits in limits {
+        if limits.memory_max.is_some() && !controllers.contains(&"memory") {
+            controllers.push("memory");
+        }
+        if limits.cpu_max.is_some() && !controllers.contains(&"cpu") {
+            controllers.push("cpu");
+        }
+    }
+    controllers
+}
+
+// `COLINK_PLAYBOOK_CGROUP`, or else the cgroup of this process, with
+// `controllers` enabled for its children.
+fn parent_cgroup(controllers: &[&str]) -> Result<PathBuf, Error> {
+    let (parent, own) = match env::var("COLINK_PLAYBOOK_CGROUP") {
+        Ok(path) => (PathBuf::from(path), false),
+        Err(_) => (own_cgroup()?, true),
+    };
+    let available = fs::read_to_string(parent.join("cgroup.controllers"))
+        .map_err(|e| format!("{} is not a cgroup v2: {e}", parent.display()))?;
+    for controller in controllers {
+        if !available.split_whitespace().any(|c| c == *controller) {
+            return Err(format!(
+                "the {controller} controller is not available in {}",
+                parent.display()
+            )
+            .into());
+        }
+    }
+    let enabled = fs::read_to_string(parent.join("cgroup.subtree_control"))?;
+    let missing: Vec<&&str> = controllers
+        .iter()
+        .filter(|controller| !enabled.split_whitespace().any(|c| c == **controller))
+        .collect();
+    if missing.is_empty() {
+        return Ok(parent);
+    }
+    // a cgroup holding processes cannot enable controllers for its children,
+    // so the operator moves into a child of its own first
+    if own
+        && !fs::read_to_string(parent.join("cgroup.procs"))?
+            .trim()
+            .is_empty()
+    {
+        let leaf = parent.join("playbook-operator");
+        if !leaf.exists() {
+            fs::create_dir(&leaf)?;
+        }
+        fs::write(leaf.join("cgroup.procs"), std::process::id().to_string())
+            .map_err(|e| format!("cannot move the playbook into {}: {e}", leaf.display()))?;
+    }
+    for controller in missing {
+        fs::write(parent.join("cgroup.subtree_control"), format!("+{controller}")).map_err(
+            |e| {
+                format!(
+                    "cannot enable the {controller} controller in {}: {e}, set COLINK_PLAYBOOK_CGROUP to a cgroup delegated to the playbook",
+                    parent.display()
+                )
+            },
+        )?;
+    }
+    Ok(parent)
+}
+
+impl Cgroup {
+    /// Create a cgroup with `memory.max` and `cpu.max` from `spec`, named
+    /// after `name`.
+    pub fn create(name: &str, spec: &LimitsSpec) -> Result<Cgroup, Error> {
+        let memory_max = spec.memory_max.as_deref().map(parse_size).transpose()?;
+        let cpu_max = spec
+            .cpu_max
+            .as_deref()
+            .map(parse_cpus)
+            .transpose()?
+            .map(|cpus| format!("{} {CPU_MAX_PERIOD}", (cpus * CPU_MAX_PERIOD as f64) as u64));
+        let controllers: Vec<&str> = [("memory", memory_max.is_some()), ("cpu", cpu_max.is_some())]
+            .into_iter()
+            .filter_map(|(controller, needed)| needed.then_some(controller))
+            .collect();
+        // set up at startup, unless no playbook seemed to need it then
+        init_cgroups(&controllers)?;
+        let parent = PARENT_CGROUP.get().unwrap().clone()?;
+        let name: String = name
+            .chars()
+            .map(|c| {
+                if c.is_ascii_alphanumeric() || c == '-' || c == '_' {
+                    c
+                } else {
+                    '_'
+                }
+            })
+            .collect();
+        let path = parent.join(format!(
+            "playbook-{name}-{}",
+            CGROUP_COUNTER.fetch_add(1, Ordering::Relaxed)
+        ));
+        fs::create_dir(&path)
+            .map_err(|e| format!("cannot create cgroup {}: {e}", path.display()))?;
+        let configured = (|| -> io::Result<fs::File> {
+            if let Some(memory_max) = memory_max {
+                fs::write(path.join("memory.max"), memory_max.to_string())?;
+            }
+            if let Some(cpu_max) = &cpu_max {
+                fs::write(path.join("cpu.max"), cpu_max)?;
+            }
+            fs::OpenOptions::new()
+                .write(true)
+                .open(path.join("cgroup.procs"))
+        })();
+        match configured {
+            Ok(procs) => Ok(Cgroup { path, procs }),
+            Err(e) => {
+                let _ = fs::remove_dir(&path);
+                Err(format!("cannot configure cgroup {}: {e}", path.display()).into())
+            }
+        }
+    }
+
+    /// The file descriptor `enter_cgroup` writes to, open until this is dropped.
+    pub fn procs_fd(&self) -> RawFd {
+        self.procs.as_raw_fd()
+    }
+}
+
+/// Move the calling process into the cgroup whose `cgroup.procs` is `fd`.
+/// This runs in the child after fork, so it only makes async-signal-safe
+/// calls.
+pub fn enter_cgroup(fd: RawFd) -> io::Result<()> {
+    // `0` is the writing process
+    if unsafe { libc::write(fd, b"0".as_ptr() as *const libc::c_void, 1) } == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Remove a cgroup once its processes are gone, it is left if some of them
+/// still run.
+pub fn remove_cgroup(path: &Path) {
+    if let Err(e) = fs::remove_dir(path) {
+        tracing::debug!(error = %e, "cgroup {} not removed", path.display());
+    }
+}
+
+/// Parse a number of CPUs like `2` or `0.5`.
+pub fn parse_cpus(s: &str) -> Result<f64, String> {
+    match s.trim().parse::<f64>() {
+        Ok(cpus) if cpus > 0.0 && cpus.is_finite() => Ok(cpus),
+        _ => Err(format!(
+            "invalid number of CPUs `{s}`, expected e.g. `2` or `0.5`"
+        )),
+    }
+}
@@ -0,0 +1,116 @@
+use crate::backend::{MemoryBackend, MemoryStorage};
+use crate::interpreter::{wait_terminated, Interpreter};
+use crate::sandbox::SandboxPolicy;
+use crate::spec_parser::ProtocolSpec;
+use colink::Participant;
+use std::{
+    collections::HashMap,
+    sync::{mpsc, Arc},
+};
+use tokio::sync::watch;
+
+type Error = Box<dyn std::error::Error + Send + Sync + 'static>;
+
+/// Run every role of `protocol_spec` in this process, with `role_nums[role]`
+/// participants per role, and return once all of them finished, or once one
+/// failed and the others were cancelled.
+pub fn local_run(
+    protocol_spec: &ProtocolSpec,
+    role_nums: &HashMap<String, usize>,
+    param: &[u8],
+    sandbox_policy: SandboxPolicy,
+) -> Result<(), Error> {
+    let task_id = format!(
+        "local-{:x}",
+        std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)?
+            .as_nanos()
+    );
+    let mut participants: Vec<Participant> = Vec::new();
+    for role in &protocol_spec.roles {
+        for i in 0..role_nums.get(&role.name).copied().unwrap_or(1) {
+            participants.push(Participant {
+                user_id: format!("{}-{}", role.name, i),
+                role: role.name.clone(),
+            });
+        }
+    }
+    println!(
+        "local run of protocol {} with task_id {task_id}",
+        protocol_spec.protocol_name
+    );
+
+    let storage = Arc::new(MemoryStorage::default());
+    let (result_sender, result_receiver) = mpsc::channel();
+    let (cancel_sender, cancel_receiver) = watch::channel(false);
+    for participant in &participants {
+        let mut role = protocol_spec
+            .roles
+            .iter()
+            .find(|role| role.name == participant.role)
+            .unwrap()
+            .clone();
+        // every participant gets its own working path, as on its own machine
+        let workdir = role
+            .workdir
+            .clone()
+            .unwrap_or(protocol_spec.workdir.clone());
+        if !workdir.contains("{{user_id") {
+            role.workdir = Some(format!(
+                "{}/{}",
+                workdir.trim_end_matches('/'),
+                participant.user_id
+            ));
+        }
+        let interpreter = Interpreter::new(protocol_spec, role, sandbox_policy);
+        let backend = MemoryBackend::new(storage.clone(), &participant.user_id, &task_id);
+        let param = param.to_vec();
+        let participants = participants.clone();
+        let user_id = participant.user_id.clone();
+        let result_sender = result_sender.clone();
+        let mut cancel_receiver = cancel_receiver.clone();
+        // every participant gets its own thread and runtime, like a task on a CoLink operator
+        std::thread::spawn(move || {
+            let res = tokio::runtime::Builder::new_current_thread()
+                .enable_all()
+                .build()
+                .unwrap()
+                .block_on(async {
+                    tokio::select! {
+                        res = interpreter.run(backend, param, participants) => {
+                            res.map_err(|e| e.to_string())
+                        }
+                        // dropping the task terminates its processes, like
+                        // a task cancelled on a CoLink operator
+                        _ = cancel_receiver.changed() => Err("cancelled".to_string()),
+                    }
+                });
+            result_sender.send((user_id, res)).unwrap();
+        });
+    }
+    drop(result_sender);
+    let mut finished = 0;
+    let mut failure = None;
+    for (user_id, res) in result_receiver {
+        match res {
+            Ok(()) => println!("{user_id}: finished"),
+            Err(e) if failure.is_some() => println!("{user_id}: {e}"),
+            // the other participants may wait for this one forever, so they
+            // are cancelled
+            Err(e) => {
+                failure = Some(format!("{user_id}: {e}"));
+                let _ = cancel_sender.send(true);
+            }
+        }
+        finished += 1;
+    }
+    wait_terminated();
+    if let Some(failure) = failure {
+        return Err(failure.into());
+    }
+    // a participant that panicked drops its sender without reporting
+    if finished < participants.len() {
+        return Err(format!("{} participant(s) panicked", participants.len() - finished).into());
+    }
+    Ok(())
+}
@@ -1,11 +1,247 @@
+mod backend;
+mod cli;
+use clap::Parser;
+use cli::{Cli, Command, OperatorArgs};
+use colink::CoLink;
+use tracing_subscriber::EnvFilter;
 mod spec_parser;
-use spec_parser::parse_spec_from_toml;
+use spec_parser::{parse_spec_from_toml, PackageSpec, SourceMap};
 mod interpreter;
 use interpreter::Interpreter;
+use sandbox::SandboxPolicy;
+mod limits;
+mod local_runner;
+mod metrics;
+mod outcome;
+mod pairing;
+mod redact;
+mod sandbox;
+mod status;
+mod validator;
 use std::fs;
+use validator::validate_package;
+
+fn load_package(
+    config: &str,
+    deny_warnings: bool,
+) -> Result<PackageSpec, Box<dyn std::error::Error + Send + Sync + 'static>> {
+    let toml_str = match fs::read_to_string(config) {
+        Ok(val) => val,
+        Err(_) => return Err(format!("Unable to read configuration file: {config}").into()),
+    };
+    let (package_spec, errors) = match parse_spec_from_toml(&toml_str) {
+        Ok(package_spec) => {
+            let mut errors = validate_package(&package_spec);
+            let source_map = SourceMap::new(&toml_str);
+            for error in &mut errors {
+                source_map.locate(error);
+            }
+            (Some(package_spec), errors)
+        }
+        Err(errors) => (None, errors.0),
+    };
+    for error in &errors {
+        eprintln!("{config}: {error}");
+    }
+    let error_num = errors
+        .iter()
+        .filter(|error| deny_warnings || !error.is_warning())
+        .count();
+    match package_spec {
+        Some(package_spec) if error_num == 0 => Ok(package_spec),
+        _ => Err(format!("{error_num} error(s) found in configuration file: {config}").into()),
+    }
+}
+
+fn validate(
+    config: &str,
+    deny_warnings: bool,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync + 'static>> {
+    let package_spec = load_package(config, deny_warnings)?;
+    let role_num: usize = package_spec
+        .iter()
+        .map(|protocol_spec| protocol_spec.roles.len())
+        .sum();
+    println!(
+        "{config}: ok ({} protocol(s), {role_num} role(s))",
+        package_spec.len()
+    );
+    Ok(())
+}
+
+fn parse_participants(
+    participants: &[String],
+) -> Result<Vec<colink::Participant>, Box<dyn std::error::Error + Send + Sync + 'static>> {
+    let mut parsed = Vec::new();
+    for participant in participants {
+        match participant.rsplit_once(':') {
+            Some((user_id, role)) => parsed.push(colink::Participant {
+                user_id: user_id.to_string(),
+                role: role.to_string(),
+            }),
+            None => {
+                return Err(format!(
+                    "participant `{participant}` should be written as USER_ID:ROLE"
+                )
+                .into())
+            }
+        }
+    }
+    Ok(parsed)
+}
+
+fn dry_run(
+    config: &str,
+    protocol: &str,
+    role: &str,
+    user_id: &str,
+    task_id: &str,
+    participants: &[String],
+) -> Result<(), Box<dyn std::error::Error + Send + Sync + 'static>> {
+    let package_spec = load_package(config, false)?;
+    let protocol_spec = match package_spec
+        .iter()
+        .find(|protocol_spec| protocol_spec.protocol_name == protocol)
+    {
+        Some(val) => val,
+        None => return Err(format!("protocol {protocol} is not defined in {config}").into()),
+    };
+    let role_spec = match protocol_spec.roles.iter().find(|r| r.name == role) {
+        Some(val) => val,
+        None => return Err(format!("role {role} is not defined in protocol {protocol}").into()),
+    };
+    let mut participants = parse_participants(participants)?;
+    if participants.is_empty() {
+        participants.push(colink::Participant {
+            user_id: user_id.to_string(),
+            role: role.to_string(),
+        });
+    }
+    // as the operator runs it without --sandbox
+    let interpreter = Interpreter::new(protocol_spec, role_spec.clone(), SandboxPolicy::default());
+    let (plan, errors) = interpreter.dry_run(user_id, task_id, &participants)?;
+    for line in plan {
+        println!("{line}");
+    }
+    if errors > 0 {
+        return Err(format!("{errors} step(s) cannot be rendered").into());
+    }
+    Ok(())
+}
+
+fn local_run(
+    config: &str,
+    protocol: &str,
+    roles: &[String],
+    param_file: &Option<String>,
+    sandbox_policy: SandboxPolicy,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync + 'static>> {
+    let package_spec = load_package(config, false)?;
+    let protocol_spec = match package_spec
+        .iter()
+        .find(|protocol_spec| protocol_spec.protocol_name == protocol)
+    {
+        Some(val) => val,
+        None => return Err(format!("protocol {protocol} is not defined in {config}").into()),
+    };
+    let mut role_nums: std::collections::HashMap<String, usize> = protocol_spec
+        .roles
+        .iter()
+        .map(|role| (role.name.clone(), role.min_num.unwrap_or(1).max(1) as usize))
+        .collect();
+    for role in roles {
+        let (name, num) = match role.split_once('=') {
+            Some((name, num)) => (name, num.parse::<usize>()?),
+            None => return Err(format!("role `{role}` should be written as ROLE=NUM").into()),
+        };
+        if !role_nums.contains_key(name) {
+            return Err(format!("role {name} is not defined in protocol {protocol}").into());
+        }
+        role_nums.insert(name.to_string(), num);
+    }
+    let param = match param_file {
+        Some(param_file) => fs::read(param_file)?,
+        None => Vec::new(),
+    };
+    init_tracing()?;
+    check_cgroups(std::slice::from_ref(protocol_spec));
+    local_runner::local_run(protocol_spec, &role_nums, &param, sandbox_policy)
+}
+
+// Set up the cgroups of the processes with `memory_max` or `cpu_max` once,
+// so that a machine where they cannot be used is reported at startup.
+fn check_cgroups(protocol_specs: &[spec_parser::ProtocolSpec]) {
+    let controllers = limits::needed_controllers(protocol_specs);
+    if controllers.is_empty() {
+        return;
+    }
+    if let Err(e) = limits::init_cgroups(&controllers) {
+        tracing::warn!(
+            error = %e,
+            "cgroups cannot be used, the processes with `memory_max` or `cpu_max` will fail to start"
+        );
+    }
+}
+
+/// Log with the filter in `RUST_LOG` (by default `info` for the playbook and
+/// `error` for everything else), in the format set by
+/// `COLINK_PLAYBOOK_LOG_FORMAT`: `full` (default), `pretty` or `json`.
+fn init_tracing() -> Result<(), Box<dyn std::error::Error + Send + Sync + 'static>> {
+    let filter = match std::env::var("RUST_LOG") {
+        Ok(val) => EnvFilter::new(val),
+        Err(_) => EnvFilter::new(format!("error,{}=info", env!("CARGO_CRATE_NAME"))),
+    };
+    let builder = tracing_subscriber::fmt().with_env_filter(filter);
+    match std::env::var("COLINK_PLAYBOOK_LOG_FORMAT").as_deref() {
+        Ok("full") | Err(_) => builder.try_init(),
+        Ok("pretty") => builder.pretty().try_init(),
+        Ok("json") => builder.json().try_init(),
+        Ok(format) => {
+            Err(format!("unknown log format {format}, expected `full`, `pretty` or `json`").into())
+        }
+    }
+}
 
 fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync + 'static>> {
-    let (cl, keep_alive_when_disconnect, vt_public_addr) = colink::_colink_parse_args();
+    if std::env::args()
+        .nth(1)
+        .is_some_and(|arg| Cli::is_subcommand(&arg))
+    {
+        return match Cli::parse().command {
+            Command::Validate {
+                config,
+                deny_warnings,
+            } => validate(&config, deny_warnings),
+            Command::DryRun {
+                config,
+                protocol,
+                role,
+                user_id,
+                task_id,
+                participants,
+            } => dry_run(&config, &protocol, &role, &user_id, &task_id, &participants),
+            Command::LocalRun {
+                config,
+                protocol,
+                roles,
+                param_file,
+                sandbox,
+            } => local_run(&config, &protocol, &roles, &param_file, sandbox.policy()),
+        };
+    }
+    // same as `colink::_colink_parse_args`, which would set up its own tracing
+    init_tracing()?;
+    let args = OperatorArgs::parse_with_subcommands();
+    if let Some(metrics_addr) = &args.metrics_addr {
+        metrics::serve(metrics_addr)?;
+    }
+    let mut cl = CoLink::new(&args.addr, &args.jwt);
+    if let Some(ca) = &args.ca {
+        cl = cl.ca_certificate(ca);
+    }
+    if let (Some(cert), Some(key)) = (&args.cert, &args.key) {
+        cl = cl.identity(cert, key);
+    }
     let config = match std::env::var("COLINK_PLAYBOOK_CONFIG") {
         Ok(val) => val,
         Err(_) => "colink.toml".to_string(),
@@ -14,18 +250,20 @@ fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync + 'static>> {
         String,
         Box<dyn colink::ProtocolEntry + Send + Sync>,
     > = std::collections::HashMap::new();
-    let toml_str = match fs::read_to_string(&config) {
-        Ok(val) => val,
-        Err(_) => return Err(format!("Unable to read configuration file: {config}").into()),
-    };
-    let protocol_spec_vec = parse_spec_from_toml(&toml_str).unwrap();
+    let protocol_spec_vec = load_package(&config, false)?;
+    check_cgroups(&protocol_spec_vec);
     for protocol_spec in protocol_spec_vec {
-        for role in protocol_spec.roles {
+        for role in &protocol_spec.roles {
             let name = protocol_spec.protocol_name.clone() + ":" + role.name.as_str();
-            let interpreter = Interpreter::new(role, &protocol_spec.workdir);
+            let interpreter = Interpreter::new(&protocol_spec, role.clone(), args.sandbox.policy());
             user_funcs.insert(name, Box::new(interpreter));
         }
     }
-    colink::_protocol_start(cl, user_funcs, keep_alive_when_disconnect, vt_public_addr)?;
+    colink::_protocol_start(
+        cl,
+        user_funcs,
+        args.keep_alive_when_disconnect,
+        args.vt_public_addr,
+    )?;
     Ok(())
 }
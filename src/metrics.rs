@@ -0,0 +1,212 @@
+use std::{
+    collections::BTreeMap,
+    fmt::Write as _,
+    io::{Read, Write},
+    net::{TcpListener, TcpStream},
+    sync::{
+        atomic::{AtomicI64, Ordering},
+        LazyLock, Mutex,
+    },
+    time::Duration,
+};
+
+type Error = Box<dyn std::error::Error + Send + Sync + 'static>;
+type Labels = Vec<(&'static str, String)>;
+
+/// Metrics of all the tasks run by this process.
+pub static METRICS: LazyLock<Metrics> = LazyLock::new(Metrics::default);
+
+pub const TASKS_STARTED: &str = "playbook_tasks_started_total";
+pub const TASKS_COMPLETED: &str = "playbook_tasks_completed_total";
+pub const TASKS_FAILED: &str = "playbook_tasks_failed_total";
+pub const STEP_DURATION: &str = "playbook_step_duration_seconds";
+pub const VARIABLE_BYTES_SENT: &str = "playbook_variable_bytes_sent_total";
+pub const VARIABLE_BYTES_RECEIVED: &str = "playbook_variable_bytes_received_total";
+pub const ENTRY_OPERATIONS: &str = "playbook_entry_operations_total";
+const PROCESSES_RUNNING: &str = "playbook_processes_running";
+
+const HELP: &[(&str, &str)] = &[
+    (TASKS_STARTED, "Tasks started."),
+    (TASKS_COMPLETED, "Tasks that finished all their steps."),
+    (TASKS_FAILED, "Tasks that stopped with an error."),
+    (STEP_DURATION, "Time spent on a step, including retries."),
+    (
+        VARIABLE_BYTES_SENT,
+        "Bytes sent by `send_variable`, counted once per receiver.",
+    ),
+    (
+        VARIABLE_BYTES_RECEIVED,
+        "Bytes received by `recv_variable`.",
+    ),
+    (ENTRY_OPERATIONS, "Entry operations of the steps."),
+    (
+        PROCESSES_RUNNING,
+        "Sub-processes started and not waited yet.",
+    ),
+];
+
+// upper bounds in seconds, steps may run from milliseconds to hours
+const BUCKETS: &[f64] = &[
+    0.01, 0.05, 0.1, 0.5, 1.0, 5.0, 10.0, 30.0, 60.0, 300.0, 600.0, 1800.0, 3600.0,
+];
+
+#[derive(Default)]
+struct Histogram {
+    // number of observations in each of `BUCKETS`, not cumulative
+    buckets: Vec<u64>,
+    sum: f64,
+    count: u64,
+}
+
+#[derive(Default)]
+pub struct Metrics {
+    counters: Mutex<BTreeMap<(&'static str, Labels), u64>>,
+    histograms: Mutex<BTreeMap<(&'static str, Labels), Histogram>>,
+    processes_running: AtomicI64,
+}
+
+fn to_labels(labels: &[(&'static str, &str)]) -> Labels {
+    labels
+        .iter()
+        .map(|(name, value)| (*name, value.to_string()))
+        .collect()
+}
+
+fn format_labels(labels: &Labels, extra: Option<(&str, &str)>) -> String {
+    let formatted: Vec<String> = labels
+        .iter()
+        .map(|(name, value)| (*name, value.as_str()))
+        .chain(extra)
+        .map(|(name, value)| {
+            let value = value
+                .replace('\\', "\\\\")
+                .replace('"', "\\\"")
+                .replace('\n', "\\n");
+            format!("{name}=\"{value}\"")
+        })
+        .collect();
+    if formatted.is_empty() {
+        String::new()
+    } else {
+        format!("{{{}}}", formatted.join(","))
+    }
+}
+
+fn write_header(out: &mut String, name: &str, metric_type: &str) {
+    let help = HELP
+        .iter()
+        .find(|(metric, _)| *metric == name)
+        .map_or("", |(_, help)| help);
+    writeln!(out, "# HELP {name} {help}").unwrap();
+    writeln!(out, "# TYPE {name} {metric_type}").unwrap();
+}
+
+impl Metrics {
+    pub fn add(&self, name: &'static str, labels: &[(&'static str, &str)], value: u64) {
+        *self
+            .counters
+            .lock()
+            .unwrap()
+            .entry((name, to_labels(labels)))
+            .or_insert(0) += value;
+    }
+
+    pub fn observe(&self, name: &'static str, labels: &[(&'static str, &str)], value: f64) {
+        let mut histograms = self.histograms.lock().unwrap();
+        let histogram = histograms.entry((name, to_labels(labels))).or_default();
+        if histogram.buckets.is_empty() {
+            histogram.buckets = vec![0; BUCKETS.len()];
+        }
+        if let Some(i) = BUCKETS.iter().position(|bound| value <= *bound) {
+            histogram.buckets[i] += 1;
+        }
+        histogram.sum += value;
+        histogram.count += 1;
+    }
+
+    pub fn add_processes(&self, value: i64) {
+        self.processes_running.fetch_add(value, Ordering::Relaxed);
+    }
+
+    /// All metrics in the Prometheus text format.
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        let mut last_name = "";
+        for ((name, labels), value) in self.counters.lock().unwrap().iter() {
+            if *name != last_name {
+                write_header(&mut out, name, "counter");
+                last_name = name;
+            }
+            writeln!(out, "{name}{} {value}", format_labels(labels, None)).unwrap();
+        }
+        for ((name, labels), histogram) in self.histograms.lock().unwrap().iter() {
+            if *name != last_name {
+                write_header(&mut out, name, "histogram");
+                last_name = name;
+            }
+            let mut cumulative = 0;
+            for (bound, count) in BUCKETS.iter().zip(&histogram.buckets) {
+                cumulative += count;
+                let le = bound.to_string();
+                let labels = format_labels(labels, Some(("le", &le)));
+                writeln!(out, "{name}_bucket{labels} {cumulative}").unwrap();
+            }
+            let inf = format_labels(labels, Some(("le", "+Inf")));
+            writeln!(out, "{name}_bucket{inf} {}", histogram.count).unwrap();
+            let labels = format_labels(labels, None);
+            writeln!(out, "{name}_sum{labels} {}", histogram.sum).unwrap();
+            writeln!(out, "{name}_count{labels} {}", histogram.count).unwrap();
+        }
+        write_header(&mut out, PROCESSES_RUNNING, "gauge");
+        writeln!(
+            out,
+            "{PROCESSES_RUNNING} {}",
+            self.processes_running.load(Ordering::Relaxed)
+        )
+        .unwrap();
+        out
+    }
+}
+
+fn respond(mut stream: TcpStream) -> Result<(), Error> {
+    stream.set_read_timeout(Some(Duration::from_secs(5)))?;
+    // only the request line matters, the rest of the request is ignored
+    let mut request = Vec::new();
+    let mut buf = [0; 1024];
+    while !request.windows(4).any(|w| w == b"\r\n\r\n") && request.len() < 8192 {
+        let n = stream.read(&mut buf)?;
+        if n == 0 {
+            break;
+        }
+        request.extend_from_slice(&buf[..n]);
+    }
+    let request = String::from_utf8_lossy(&request);
+    let mut request_line = request.lines().next().unwrap_or("").split(' ');
+    let (status, body) = match (request_line.next(), request_line.next()) {
+        (Some("GET"), Some("/metrics")) => ("200 OK", METRICS.render()),
+        _ => ("404 Not Found", "not found, try /metrics\n".to_string()),
+    };
+    write!(
+        stream,
+        "HTTP/1.1 {status}\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+        body.len()
+    )?;
+    Ok(())
+}
+
+/// Serve `METRICS` on `http://{addr}/metrics` from a background thread.
+pub fn serve(addr: &str) -> Result<(), Error> {
+    let listener = TcpListener::bind(addr)?;
+    tracing::info!(
+        "serving metrics on http://{}/metrics",
+        listener.local_addr()?
+    );
+    std::thread::spawn(move || {
+        for stream in listener.incoming() {
+            if let Err(e) = stream.map_err(Error::from).and_then(respond) {
+                tracing::warn!(error = %e, "failed to serve metrics");
+            }
+        }
+    });
+    Ok(())
+}
@@ -0,0 +1,72 @@
+use crate::spec_parser::signal_name;
+use serde::{Serialize, Serializer};
+use serde_json::json;
+use std::{os::unix::process::ExitStatusExt, process::ExitStatus};
+
+/// How a sub-process ended.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum ProcessOutcome {
+    Exited {
+        code: i32,
+    },
+    Signaled {
+        signal: i32,
+    },
+    /// killed by the playbook after the `timeout` of the step
+    TimedOut,
+}
+
+impl ProcessOutcome {
+    pub fn from_status(status: ExitStatus) -> ProcessOutcome {
+        match status.signal() {
+            Some(signal) => ProcessOutcome::Signaled { signal },
+            None => ProcessOutcome::Exited {
+                code: status.code().unwrap(),
+            },
+        }
+    }
+
+    pub fn is_success(self) -> bool {
+        self == ProcessOutcome::Exited { code: 0 }
+    }
+
+    /// The number written by `exit_code_format = "code"`, where a signal is
+    /// reported by its number as if it were an exit code.
+    pub fn code(self) -> i32 {
+        match self {
+            ProcessOutcome::Exited { code } => code,
+            ProcessOutcome::Signaled { signal } => signal,
+            ProcessOutcome::TimedOut => libc::SIGKILL,
+        }
+    }
+
+    pub fn to_json(self) -> serde_json::Value {
+        match self {
+            ProcessOutcome::Exited { code } => json!({"outcome": "exited", "code": code}),
+            ProcessOutcome::Signaled { signal } => json!({
+                "outcome": "signaled",
+                "signal": signal,
+                "signal_name": signal_name(signal),
+            }),
+            ProcessOutcome::TimedOut => json!({"outcome": "timed_out"}),
+        }
+    }
+}
+
+impl std::fmt::Display for ProcessOutcome {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ProcessOutcome::Exited { code } => write!(f, "exits with {code}"),
+            ProcessOutcome::Signaled { signal } => {
+                write!(f, "is terminated by {}", signal_name(*signal))
+            }
+            ProcessOutcome::TimedOut => write!(f, "times out"),
+        }
+    }
+}
+
+impl Serialize for ProcessOutcome {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        self.to_json().serialize(serializer)
+    }
+}
@@ -0,0 +1,283 @@
+use crate::spec_parser::{ProtocolSpec, SpecError, SpecErrorKind};
+use std::collections::HashMap;
+
+// one `send_variable` or `recv_variable` step, `peer` is its `to_role`/`from_role`
+struct Transfer<'a> {
+    step: usize,
+    name: &'a str,
+    peer: &'a str,
+}
+
+struct RoleTransfers<'a> {
+    role: &'a str,
+    steps: usize,
+    sends: Vec<Transfer<'a>>,
+    recvs: Vec<Transfer<'a>>,
+}
+
+fn warning(path: String, field: &str, message: String) -> SpecError {
+    SpecError::new(&path, Some(field), SpecErrorKind::Warning(message))
+}
+
+/// Match every `send_variable` of a protocol against a `recv_variable` of the
+/// receiving role and warn about transfers without a counterpart and about
+/// roles that block on each other's `recv_variable` forever.
+///
+/// The n-th send of a variable name from role A to role B is paired with the
+/// n-th receive of the same name (before rendering) in role B from role A.
+/// Conditional steps are assumed to run.
+pub fn check_variable_pairing(protocol_spec: &ProtocolSpec) -> Vec<SpecError> {
+    let mut warnings: Vec<SpecError> = Vec::new();
+    let roles: Vec<RoleTransfers> = protocol_spec
+        .roles
+        .iter()
+        .map(|role_spec| {
+            let mut transfers = RoleTransfers {
+                role: &role_spec.name,
+                steps: role_spec.steps.len(),
+                sends: Vec::new(),
+                recvs: Vec::new(),
+            };
+            for (i, step) in role_spec.steps.iter().enumerate() {
+                if let (Some(name), Some(peer)) = (&step.send_variable, &step.to_role) {
+                    transfers.sends.push(Transfer {
+                        step: i,
+                        name,
+                        peer,
+                    });
+                }
+                if let (Some(name), Some(peer)) = (&step.recv_variable, &step.from_role) {
+                    transfers.recvs.push(Transfer {
+                        step: i,
+                        name,
+                        peer,
+                    });
+                }
+            }
+            transfers
+        })
+        .collect();
+    let find_role = |name: &str| roles.iter().find(|role| role.role == name);
+
+    // (receiving role, recv step) -> (sending role, send step)
+    let mut recv_to_send: HashMap<(&str, usize), (&str, usize)> = HashMap::new();
+    for sender in &roles {
+        let mut nth_send: HashMap<(&str, &str), usize> = HashMap::new();
+        for send in &sender.sends {
+            let receiver = match find_role(send.peer) {
+                Some(receiver) => receiver,
+                // unknown roles are reported by the validator
+                None => continue,
+            };
+            let n = nth_send.entry((send.peer, send.name)).or_insert(0);
+            let recv = receiver
+                .recvs
+                .iter()
+                .filter(|recv| recv.name == send.name && recv.peer == sender.role)
+                .nth(*n);
+            *n += 1;
+            match recv {
+                Some(recv) => {
+                    recv_to_send.insert((receiver.role, recv.step), (sender.role, send.step));
+                }
+                None => warnings.push(warning(
+                    protocol_spec.step_path(sender.role, send.step),
+                    "send_variable",
+                    format!(
+                        "variable `{}` sent to `{}` is never received by `{}` from `{}`",
+                        send.name, send.peer, send.peer, sender.role
+                    ),
+                )),
+            }
+        }
+    }
+    for receiver in &roles {
+        for recv in &receiver.recvs {
+            if find_role(recv.peer).is_some()
+                && !recv_to_send.contains_key(&(receiver.role, recv.step))
+            {
+                warnings.push(warning(
+                    protocol_spec.step_path(receiver.role, recv.step),
+                    "recv_variable",
+                    format!(
+                        "variable `{}` is never sent by `{}` to `{}`, this step will block forever",
+                        recv.name, recv.peer, receiver.role
+                    ),
+                ));
+            }
+        }
+    }
+
+    // run all roles step by step, a receive only proceeds once its sender has
+    // passed the matching send; roles left behind wait on each other forever
+    let mut position: HashMap<&str, usize> = roles.iter().map(|role| (role.role, 0)).collect();
+    loop {
+        let mut progress = false;
+        for role in &roles {
+            loop {
+                let pos = position[role.role];
+                if pos >= role.steps {
+                    break;
+                }
+                if let Some((sender, send_step)) = recv_to_send.get(&(role.role, pos)) {
+                    if position[sender] <= *send_step {
+                        break;
+                    }
+                }
+                position.insert(role.role, pos + 1);
+                progress = true;
+            }
+        }
+        if !progress {
+            break;
+        }
+    }
+    let blocked_on = |role: &str| -> Option<(&str, usize)> {
+        let pos = position[role];
+        recv_to_send
+            .get(&(role, pos))
+            .map(|(sender, _)| (*sender, pos))
+    };
+    let mut reported: Vec<&str> = Vec::new();
+    for role in &roles {
+        if blocked_on(role.role).is_none() || reported.contains(&role.role) {
+            continue;
+        }
+        // follow the wait-for chain until it loops back
+        let mut chain: Vec<&str> = vec![role.role];
+        let mut current = role.role;
+        while let Some((sender, _)) = blocked_on(current) {
+            if let Some(start) = chain.iter().position(|r| *r == sender) {
+                chain.drain(..start);
+                break;
+            }
+            chain.push(sender);
+            current = sender;
+        }
+        if chain.iter().any(|r| reported.contains(r)) {
+            continue;
+        }
+        let description: Vec<String> = chain
+            .iter()
+            .map(|r| {
+                let (sender, step) = blocked_on(r).unwrap();
+                let recv = find_role(r)
+                    .unwrap()
+                    .recvs
+                    .iter()
+                    .find(|recv| recv.step == step)
+                    .unwrap();
+                format!(
+                    "`{r}` waits at steps[{step}] for `{}` from `{sender}`",
+                    recv.name
+                )
+            })
+            .collect();
+        let (_, step) = blocked_on(chain[0]).unwrap();
+        warnings.push(warning(
+            protocol_spec.step_path(chain[0], step),
+            "recv_variable",
+            format!("deadlock: {}", description.join(", ")),
+        ));
+        reported.extend(chain);
+    }
+    warnings
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::spec_parser::parse_spec_from_toml;
+
+    fn warnings(roles: &str) -> Vec<String> {
+        let toml_str = format!(
+            "[package]\nuse_playbook = true\n[t]\nname = \"t\"\nworkdir = \"/tmp\"\n{roles}"
+        );
+        let package_spec = parse_spec_from_toml(&toml_str).unwrap_or_else(|e| panic!("{e}"));
+        check_variable_pairing(&package_spec[0])
+            .iter()
+            .map(|warning| warning.to_string())
+            .collect()
+    }
+
+    #[test]
+    fn paired_transfers() {
+        let warnings = warnings(
+            r#"
+[[t.roles.a.playbook.steps]]
+send_variable = "x"
+file = "x"
+to_role = "b"
+[[t.roles.a.playbook.steps]]
+recv_variable = "y"
+file = "y"
+from_role = "b"
+index = 0
+[[t.roles.b.playbook.steps]]
+recv_variable = "x"
+file = "x"
+from_role = "a"
+index = 0
+[[t.roles.b.playbook.steps]]
+send_variable = "y"
+file = "y"
+to_role = "a"
+"#,
+        );
+        assert!(warnings.is_empty(), "{warnings:?}");
+    }
+
+    #[test]
+    fn unpaired_transfers() {
+        let warnings = warnings(
+            r#"
+[[t.roles.a.playbook.steps]]
+send_variable = "x"
+file = "x"
+to_role = "b"
+[[t.roles.b.playbook.steps]]
+recv_variable = "z"
+file = "z"
+from_role = "a"
+index = 0
+"#,
+        );
+        assert_eq!(
+            warnings,
+            [
+                "warning: t.roles.a.playbook.steps[0].send_variable: variable `x` sent to `b` is never received by `b` from `a`",
+                "warning: t.roles.b.playbook.steps[0].recv_variable: variable `z` is never sent by `a` to `b`, this step will block forever",
+            ]
+        );
+    }
+
+    #[test]
+    fn deadlock() {
+        let warnings = warnings(
+            r#"
+[[t.roles.a.playbook.steps]]
+recv_variable = "y"
+file = "y"
+from_role = "b"
+index = 0
+[[t.roles.a.playbook.steps]]
+send_variable = "x"
+file = "x"
+to_role = "b"
+[[t.roles.b.playbook.steps]]
+recv_variable = "x"
+file = "x"
+from_role = "a"
+index = 0
+[[t.roles.b.playbook.steps]]
+send_variable = "y"
+file = "y"
+to_role = "a"
+"#,
+        );
+        assert_eq!(
+            warnings,
+            ["warning: t.roles.a.playbook.steps[0].recv_variable: deadlock: `a` waits at steps[0] for `y` from `b`, `b` waits at steps[0] for `x` from `a`"]
+        );
+    }
+}
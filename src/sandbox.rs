@@ -0,0 +1,217 @@
+use std::{
+    ffi::{CStr, CString},
+    io,
+    os::unix::ffi::OsStrExt,
+    path::Path,
+};
+
+type Error = Box<dyn std::error::Error + Send + Sync + 'static>;
+
+// from <linux/mount.h>, not in libc yet
+const MOUNT_ATTR_RDONLY: u64 = 0x1;
+
+// `nobody`, the id inside the sandbox of an operator running as root, which
+// must not be root there: it would keep every capability across exec and
+// could make the file system writable again
+const UNPRIVILEGED_ID: libc::uid_t = 65534;
+
+#[repr(C)]
+struct MountAttr {
+    attr_set: u64,
+    attr_clr: u64,
+    propagation: u64,
+    userns_fd: u64,
+}
+
+/// How the operator sandboxes the processes of every playbook, on top of
+/// what the playbooks ask for.
+#[derive(Clone, Copy, Default)]
+pub struct SandboxPolicy {
+    /// run every process in the sandbox, whatever the playbook sets
+    pub force: bool,
+    /// with `force`, let the playbooks give their processes the network
+    pub allow_network: bool,
+}
+
+impl SandboxPolicy {
+    /// Whether a process runs in the sandbox given the `sandbox` and
+    /// `sandbox_network` a playbook sets for it: `None` if it does not,
+    /// otherwise if it has the network.
+    pub fn resolve(&self, sandbox: bool, network: bool) -> Option<bool> {
+        if self.force {
+            Some(network && self.allow_network)
+        } else if sandbox {
+            Some(network)
+        } else {
+            None
+        }
+    }
+}
+
+/// A sandbox for one process, entered between fork and exec: new user and
+/// mount namespaces with a read-only root except the working dir, a private
+/// `/tmp`, and a network namespace with only loopback unless `network`.
+pub struct Sandbox {
+    network: bool,
+    working_dir: CString,
+    private_tmp: bool,
+    drop_groups: bool,
+    uid_map: Vec<u8>,
+    gid_map: Vec<u8>,
+}
+
+impl Sandbox {
+    pub fn new(working_dir: &Path, network: bool) -> Result<Sandbox, Error> {
+        let working_dir = std::path::absolute(working_dir)?;
+        // a tmpfs on `/tmp` would hide a working dir in it
+        let private_tmp = !working_dir.starts_with("/tmp");
+        // the ids of the operator are kept inside unless they are root, a
+        // process is never root in the sandbox
+        let (uid, gid) = unsafe { (libc::getuid(), libc::getgid()) };
+        let inside = |id: libc::uid_t| if id == 0 { UNPRIVILEGED_ID } else { id };
+        Ok(Sandbox {
+            network,
+            working_dir: CString::new(working_dir.as_os_str().as_bytes())?,
+            private_tmp,
+            // the groups of root are dropped, as other users cannot
+            drop_groups: uid == 0,
+            uid_map: format!("{} {uid} 1", inside(uid)).into_bytes(),
+            gid_map: format!("{} {gid} 1", inside(gid)).into_bytes(),
+        })
+    }
+
+    /// Move the calling process into the sandbox. This runs in the child
+    /// after fork, so it only makes async-signal-safe calls.
+    pub fn enter(&self) -> io::Result<()> {
+        if self.drop_groups {
+            check(unsafe { libc::setgroups(0, std::ptr::null()) })?;
+        }
+        let mut namespaces = libc::CLONE_NEWUSER | libc::CLONE_NEWNS;
+        if !self.network {
+            namespaces |= libc::CLONE_NEWNET;
+        }
+        check(unsafe { libc::unshare(namespaces) })?;
+        write_file(c"/proc/self/setgroups", b"deny")?;
+        write_file(c"/proc/self/uid_map", &self.uid_map)?;
+        write_file(c"/proc/self/gid_map", &self.gid_map)?;
+
+        // keep the mounts below from reaching the namespace of the operator
+        mount(None, c"/", None, libc::MS_REC | libc::MS_PRIVATE, None)?;
+        let working_dir = self.working_dir.as_c_str();
+        mount(
+            Some(working_dir),
+            working_dir,
+            None,
+            libc::MS_BIND | libc::MS_REC,
+            None,
+        )?;
+        set_read_only(c"/", true, libc::AT_RECURSIVE)?;
+        set_read_only(working_dir, false, 0)?;
+        if self.private_tmp {
+            mount(
+                Some(c"tmpfs"),
+                c"/tmp",
+                Some(c"tmpfs"),
+                libc::MS_NOSUID | libc::MS_NODEV,
+                Some(c"mode=1777"),
+            )?;
+        }
+        if !self.network {
+            loopback_up()?;
+        }
+        // the working dir was entered before it was mounted again
+        check(unsafe { libc::chdir(working_dir.as_ptr()) })?;
+        drop_privileges()
+    }
+}
+
+// The capabilities the process has in its namespace are lost on exec, as it
+// is not root there. Neither they nor setuid programs can give it more.
+fn drop_privileges() -> io::Result<()> {
+    for cap in 0.. {
+        if unsafe { libc::prctl(libc::PR_CAPBSET_DROP, cap, 0, 0, 0) } == -1 {
+            let e = io::Error::last_os_error();
+            // past the last capability of the kernel
+            if e.raw_os_error() == Some(libc::EINVAL) {
+                break;
+            }
+            return Err(e);
+        }
+    }
+    check(unsafe { libc::prctl(libc::PR_SET_NO_NEW_PRIVS, 1, 0, 0, 0) })
+}
+
+fn check(ret: libc::c_int) -> io::Result<()> {
+    if ret == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+fn write_file(path: &CStr, data: &[u8]) -> io::Result<()> {
+    let fd = unsafe { libc::open(path.as_ptr(), libc::O_WRONLY | libc::O_CLOEXEC) };
+    check(fd)?;
+    let written = unsafe { libc::write(fd, data.as_ptr() as *const libc::c_void, data.len()) };
+    let res = if written == -1 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(())
+    };
+    unsafe { libc::close(fd) };
+    res
+}
+
+fn mount(
+    source: Option<&CStr>,
+    target: &CStr,
+    fs_type: Option<&CStr>,
+    flags: libc::c_ulong,
+    data: Option<&CStr>,
+) -> io::Result<()> {
+    let ptr = |s: Option<&CStr>| s.map_or(std::ptr::null(), CStr::as_ptr);
+    check(unsafe {
+        libc::mount(
+            ptr(source),
+            target.as_ptr(),
+            ptr(fs_type),
+            flags,
+            ptr(data).cast(),
+        )
+    })
+}
+
+// `mount_setattr` (Linux 5.12), which unlike a remount also changes the
+// mounts below `path` with `AT_RECURSIVE`
+fn set_read_only(path: &CStr, read_only: bool, flags: libc::c_int) -> io::Result<()> {
+    let attr = MountAttr {
+        attr_set: if read_only { MOUNT_ATTR_RDONLY } else { 0 },
+        attr_clr: if read_only { 0 } else { MOUNT_ATTR_RDONLY },
+        propagation: 0,
+        userns_fd: 0,
+    };
+    let ret = unsafe {
+        libc::syscall(
+            libc::SYS_mount_setattr,
+            libc::AT_FDCWD,
+            path.as_ptr(),
+            flags,
+            &attr as *const MountAttr,
+            std::mem::size_of::<MountAttr>(),
+        )
+    };
+    check(ret as libc::c_int)
+}
+
+// A new network namespace has a loopback interface, but it is down.
+fn loopback_up() -> io::Result<()> {
+    let fd = unsafe { libc::socket(libc::AF_INET, libc::SOCK_DGRAM | libc::SOCK_CLOEXEC, 0) };
+    check(fd)?;
+    let mut request: libc::ifreq = unsafe { std::mem::zeroed() };
+    for (dst, src) in request.ifr_name.iter_mut().zip(b"lo") {
+        *dst = *src as libc::c_char;
+    }
+    request.ifr_ifru.ifru_flags = (libc::IFF_UP | libc::IFF_RUNNING) as libc::c_short;
+    let res = check(unsafe { libc::ioctl(fd, libc::SIOCSIFFLAGS, &request) });
+    unsafe { libc::close(fd) };
+    res
+}
@@ -1,4 +1,9 @@
+use regex::Regex;
 use serde::Deserialize;
+use std::{
+    collections::{BTreeMap, HashMap},
+    time::Duration,
+};
 use toml::Value;
 
 #[derive(Deserialize, Clone)]
@@ -7,9 +12,29 @@ pub struct StepSpec {
     pub _if: Option<String>,
     pub step_name: Option<String>,
     pub process: Option<String>,
+    /// a program and its arguments, run directly instead of through a shell
+    pub argv: Option<Vec<String>>,
+    /// the shell running `process` and `if`, e.g. `sh` or `bash -euo pipefail`
+    pub shell: Option<String>,
+    /// environment variables of `process` and `if`, over those of the role
+    pub env: Option<BTreeMap<String, String>>,
+    /// a file the process reads on stdin
+    pub stdin_file: Option<String>,
+    /// text the process reads on stdin
+    pub stdin: Option<String>,
+    /// limits of the process, over those of the role
+    pub limits: Option<LimitsSpec>,
+    /// run the process in the sandbox, over `sandbox` of the role
+    pub sandbox: Option<bool>,
+    /// give the process in the sandbox the network
+    pub sandbox_network: Option<bool>,
     pub process_wait: Option<String>,
     pub process_kill: Option<String>,
-    pub check_exit_code: Option<i32>,
+    pub process_stop: Option<String>,
+    pub check_exit_code: Option<CheckExitCode>,
+    pub check_signal: Option<String>,
+    pub expect_success: Option<bool>,
+    pub allow_failure: Option<bool>,
     pub send_variable: Option<String>,
     pub recv_variable: Option<String>,
     pub from_role: Option<String>,
@@ -20,18 +45,483 @@ pub struct StepSpec {
     pub stdout_file: Option<String>,
     pub stderr_file: Option<String>,
     pub exit_code: Option<String>,
+    pub exit_code_format: Option<ExitCodeFormat>,
     pub create_entry: Option<String>,
     pub update_entry: Option<String>,
     pub delete_entry: Option<String>,
     pub read_entry: Option<String>,
     pub read_or_wait_entry: Option<String>,
+    pub timeout: Option<String>,
+    pub retry: Option<RetrySpec>,
+    pub log_output: Option<bool>,
+    pub signal: Option<String>,
+    pub grace_period: Option<String>,
 }
 
+/// An exit code like `3`, or a string with a code or an inclusive range like
+/// `"2-5"`, negated with a leading `!`.
+#[derive(Deserialize, Clone)]
+#[serde(untagged)]
+pub enum ExitCodePattern {
+    Code(i32),
+    Pattern(String),
+}
+
+impl ExitCodePattern {
+    // (negated, lowest code, highest code)
+    fn parse(&self) -> Result<(bool, i32, i32), String> {
+        let pattern = match self {
+            ExitCodePattern::Code(code) => return Ok((false, *code, *code)),
+            ExitCodePattern::Pattern(pattern) => pattern.trim(),
+        };
+        let (negated, range) = match pattern.strip_prefix('!') {
+            Some(range) => (true, range.trim()),
+            None => (false, pattern),
+        };
+        let parse_code = |code: &str| {
+            code.trim().parse::<i32>().map_err(|_| {
+                format!("invalid exit code pattern `{pattern}`, expected e.g. `3`, `\"2-5\"` or `\"!0\"`")
+            })
+        };
+        let (low, high) = match range.split_once('-') {
+            Some((low, high)) => (parse_code(low)?, parse_code(high)?),
+            None => (parse_code(range)?, parse_code(range)?),
+        };
+        if low > high {
+            return Err(format!("empty exit code range `{pattern}`"));
+        }
+        Ok((negated, low, high))
+    }
+}
+
+impl std::fmt::Display for ExitCodePattern {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ExitCodePattern::Code(code) => write!(f, "{code}"),
+            ExitCodePattern::Pattern(pattern) => write!(f, "{pattern}"),
+        }
+    }
+}
+
+/// `check_exit_code`: one pattern or a list of them. A code matches when it
+/// matches one of the patterns that are not negated (if there are any) and
+/// none of the negated ones, e.g. `["1-10", "!5"]`.
+#[derive(Deserialize, Clone)]
+#[serde(untagged)]
+pub enum CheckExitCode {
+    One(ExitCodePattern),
+    List(Vec<ExitCodePattern>),
+}
+
+impl CheckExitCode {
+    fn patterns(&self) -> &[ExitCodePattern] {
+        match self {
+            CheckExitCode::One(pattern) => std::slice::from_ref(pattern),
+            CheckExitCode::List(patterns) => patterns,
+        }
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        // it would accept every exit code
+        if self.patterns().is_empty() {
+            return Err("`check_exit_code` should list at least one exit code".to_string());
+        }
+        for pattern in self.patterns() {
+            pattern.parse()?;
+        }
+        Ok(())
+    }
+
+    pub fn matches(&self, code: i32) -> Result<bool, String> {
+        self.validate()?;
+        let mut has_allowed = false;
+        let mut allowed = false;
+        for pattern in self.patterns() {
+            let (negated, low, high) = pattern.parse()?;
+            let in_range = (low..=high).contains(&code);
+            if negated && in_range {
+                return Ok(false);
+            }
+            if !negated {
+                has_allowed = true;
+                allowed |= in_range;
+            }
+        }
+        Ok(allowed || !has_allowed)
+    }
+}
+
+impl std::fmt::Display for CheckExitCode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CheckExitCode::One(pattern) => write!(f, "{pattern}"),
+            CheckExitCode::List(patterns) => {
+                let patterns: Vec<String> = patterns.iter().map(|p| p.to_string()).collect();
+                write!(f, "[{}]", patterns.join(", "))
+            }
+        }
+    }
+}
+
+#[derive(Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum ExitCodeFormat {
+    /// only the exit code, or the number of the signal that terminated the process
+    Code,
+    /// the outcome of the process as JSON, see `ProcessOutcome`
+    Json,
+}
+
+#[derive(Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Backoff {
+    Fixed,
+    Exponential,
+}
+
+#[derive(Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum RetryOn {
+    /// the process does not end as `check_exit_code`, `check_signal` or
+    /// `expect_success` expects
+    ExitCode,
+    /// a CoLink operation (variable or entry) returns an error
+    Colink,
+}
+
+#[derive(Deserialize, Clone)]
+pub struct RetrySpec {
+    /// total number of attempts, including the first one
+    pub max_attempts: i64,
+    pub backoff: Option<Backoff>,
+    pub delay: Option<String>,
+    pub max_delay: Option<String>,
+    pub on: Option<Vec<RetryOn>>,
+    /// only retry on these exit codes instead of any mismatching one
+    pub exit_codes: Option<Vec<i32>>,
+}
+
+/// Resources a process may use, applied when it starts.
+#[derive(Deserialize, Clone, Default)]
+pub struct LimitsSpec {
+    /// CPU time, e.g. `10m`, the process gets SIGXCPU and then SIGKILL
+    pub cpu_time: Option<String>,
+    /// virtual memory of each process, e.g. `4G`
+    pub address_space: Option<String>,
+    pub open_files: Option<i64>,
+    /// from -20 (highest priority) to 19 (lowest)
+    pub nice: Option<i64>,
+    /// `memory.max` of a cgroup holding the process and its children
+    pub memory_max: Option<String>,
+    /// number of CPUs of a cgroup holding the process and its children, e.g.
+    /// `"1.5"`
+    pub cpu_max: Option<String>,
+}
+
+impl LimitsSpec {
+    /// These limits, with the unset ones taken from `defaults`.
+    pub fn or(&self, defaults: &LimitsSpec) -> LimitsSpec {
+        LimitsSpec {
+            cpu_time: self.cpu_time.clone().or(defaults.cpu_time.clone()),
+            address_space: self
+                .address_space
+                .clone()
+                .or(defaults.address_space.clone()),
+            open_files: self.open_files.or(defaults.open_files),
+            nice: self.nice.or(defaults.nice),
+            memory_max: self.memory_max.clone().or(defaults.memory_max.clone()),
+            cpu_max: self.cpu_max.clone().or(defaults.cpu_max.clone()),
+        }
+    }
+}
+
+impl RetrySpec {
+    pub fn retries_on(&self, failure: RetryOn) -> bool {
+        match &self.on {
+            Some(on) => on.contains(&failure),
+            None => true,
+        }
+    }
+}
+
+// every key accepted in a step table, checked before handing the table to serde
+const STEP_FIELDS: &[(&str, FieldType)] = &[
+    ("if", FieldType::String),
+    ("step_name", FieldType::String),
+    ("process", FieldType::String),
+    ("argv", FieldType::Array),
+    ("shell", FieldType::String),
+    ("env", FieldType::Table),
+    ("stdin_file", FieldType::String),
+    ("stdin", FieldType::String),
+    ("limits", FieldType::Table),
+    ("sandbox", FieldType::Boolean),
+    ("sandbox_network", FieldType::Boolean),
+    ("process_wait", FieldType::String),
+    ("process_kill", FieldType::String),
+    ("process_stop", FieldType::String),
+    ("check_exit_code", FieldType::ExitCodes),
+    ("check_signal", FieldType::String),
+    ("expect_success", FieldType::Boolean),
+    ("allow_failure", FieldType::Boolean),
+    ("send_variable", FieldType::String),
+    ("recv_variable", FieldType::String),
+    ("from_role", FieldType::String),
+    ("to_role", FieldType::String),
+    ("index", FieldType::Integer),
+    ("file", FieldType::String),
+    ("stdout_file", FieldType::String),
+    ("stderr_file", FieldType::String),
+    ("exit_code", FieldType::String),
+    ("exit_code_format", FieldType::String),
+    ("create_entry", FieldType::String),
+    ("update_entry", FieldType::String),
+    ("delete_entry", FieldType::String),
+    ("read_entry", FieldType::String),
+    ("read_or_wait_entry", FieldType::String),
+    ("timeout", FieldType::String),
+    ("retry", FieldType::Table),
+    ("log_output", FieldType::Boolean),
+    ("signal", FieldType::String),
+    ("grace_period", FieldType::String),
+];
+
+const LIMITS_FIELDS: &[(&str, FieldType)] = &[
+    ("cpu_time", FieldType::String),
+    ("address_space", FieldType::String),
+    ("open_files", FieldType::Integer),
+    ("nice", FieldType::Integer),
+    ("memory_max", FieldType::String),
+    ("cpu_max", FieldType::String),
+];
+
+const RETRY_FIELDS: &[(&str, FieldType)] = &[
+    ("max_attempts", FieldType::Integer),
+    ("backoff", FieldType::String),
+    ("delay", FieldType::String),
+    ("max_delay", FieldType::String),
+    ("on", FieldType::Array),
+    ("exit_codes", FieldType::Array),
+];
+
 impl StepSpec {
-    pub fn new(value: &Value) -> Result<StepSpec, Box<dyn std::error::Error>> {
-        let step_spec: StepSpec = toml::from_str(&value.to_string()).unwrap();
-        Ok(step_spec)
+    pub fn new(path: &str, value: &Value, errors: &mut Vec<SpecError>) -> Option<StepSpec> {
+        let table = match value.as_table() {
+            Some(table) => table,
+            None => {
+                errors.push(SpecError::new(
+                    path,
+                    None,
+                    SpecErrorKind::WrongType {
+                        expected: FieldType::Table.name(),
+                        found: value.type_str(),
+                    },
+                ));
+                return None;
+            }
+        };
+        let errors_before = errors.len();
+        check_fields(path, table, STEP_FIELDS, errors);
+        if let Some(Value::Table(retry)) = table.get("retry") {
+            check_fields(&format!("{path}.retry"), retry, RETRY_FIELDS, errors);
+        }
+        if let Some(Value::Table(limits)) = table.get("limits") {
+            check_fields(&format!("{path}.limits"), limits, LIMITS_FIELDS, errors);
+        }
+        if let Some(Value::Table(_)) = table.get("env") {
+            env_field(path, value, errors);
+        }
+        if errors.len() > errors_before {
+            return None;
+        }
+        match value.clone().try_into::<StepSpec>() {
+            Ok(step_spec) => Some(step_spec),
+            Err(e) => {
+                errors.push(SpecError::new(
+                    path,
+                    None,
+                    SpecErrorKind::Invalid(e.to_string()),
+                ));
+                None
+            }
+        }
+    }
+
+    /// Whether this step starts a process, with `process` or `argv`.
+    pub fn starts_process(&self) -> bool {
+        self.process.is_some() || self.argv.is_some()
+    }
+
+    /// The name of the action this step performs, e.g. `process_wait`.
+    pub fn action(&self) -> &'static str {
+        let actions = [
+            ("process_wait", self.process_wait.is_some()),
+            ("process_kill", self.process_kill.is_some()),
+            ("process_stop", self.process_stop.is_some()),
+            ("process", self.starts_process()),
+            ("send_variable", self.send_variable.is_some()),
+            ("recv_variable", self.recv_variable.is_some()),
+            ("create_entry", self.create_entry.is_some()),
+            ("read_entry", self.read_entry.is_some()),
+            ("read_or_wait_entry", self.read_or_wait_entry.is_some()),
+            ("update_entry", self.update_entry.is_some()),
+            ("delete_entry", self.delete_entry.is_some()),
+        ];
+        actions
+            .iter()
+            .find(|(_, is_set)| *is_set)
+            .map_or("none", |(action, _)| action)
+    }
+}
+
+const SIGNALS: &[(&str, i32)] = &[
+    ("HUP", libc::SIGHUP),
+    ("INT", libc::SIGINT),
+    ("QUIT", libc::SIGQUIT),
+    ("ILL", libc::SIGILL),
+    ("ABRT", libc::SIGABRT),
+    ("BUS", libc::SIGBUS),
+    ("FPE", libc::SIGFPE),
+    ("KILL", libc::SIGKILL),
+    ("USR1", libc::SIGUSR1),
+    ("SEGV", libc::SIGSEGV),
+    ("USR2", libc::SIGUSR2),
+    ("PIPE", libc::SIGPIPE),
+    ("ALRM", libc::SIGALRM),
+    ("TERM", libc::SIGTERM),
+    ("XCPU", libc::SIGXCPU),
+    ("XFSZ", libc::SIGXFSZ),
+];
+
+/// Parse signal names like `SIGTERM`, with or without `SIG`.
+pub fn parse_signal(s: &str) -> Result<i32, String> {
+    let name = s.strip_prefix("SIG").unwrap_or(s);
+    match SIGNALS.iter().find(|(signal_name, _)| *signal_name == name) {
+        Some((_, signal)) => Ok(*signal),
+        None => Err(format!(
+            "unknown signal `{s}`, expected a name like `SIGTERM`, `SIGINT` or `SIGKILL`"
+        )),
+    }
+}
+
+/// The name of a signal like `SIGTERM`, or its number if it has no name here.
+pub fn signal_name(signal: i32) -> String {
+    match SIGNALS.iter().find(|(_, number)| *number == signal) {
+        Some((name, _)) => format!("SIG{name}"),
+        None => signal.to_string(),
+    }
+}
+
+/// The program and arguments running `command` with `shell`: the words of
+/// the shell, `-c` unless they already end with it, then the command. With
+/// `none` the command is split into words and run directly. The default shell
+/// is `bash`.
+pub fn shell_argv(shell: Option<&str>, command: &str) -> Result<Vec<String>, String> {
+    let shell = shell.unwrap_or("bash").trim();
+    if shell == "none" {
+        let argv: Vec<String> = command.split_whitespace().map(String::from).collect();
+        if argv.is_empty() {
+            return Err("empty command with `shell = \"none\"`".to_string());
+        }
+        return Ok(argv);
+    }
+    let mut argv: Vec<String> = shell.split_whitespace().map(String::from).collect();
+    if argv.is_empty() {
+        return Err(
+            "empty `shell`, expected e.g. `sh`, `bash -euo pipefail` or `none`".to_string(),
+        );
+    }
+    if argv.last().map(String::as_str) != Some("-c") {
+        argv.push("-c".to_string());
+    }
+    argv.push(command.to_string());
+    Ok(argv)
+}
+
+/// Parse sizes in bytes like `65536`, `512K`, `4G` or `1.5G`, in powers of
+/// 1024.
+pub fn parse_size(s: &str) -> Result<u64, String> {
+    let re = Regex::new(r"^(\d+(?:\.\d+)?)\s*([KMGT]?)i?B?$").unwrap();
+    let caps = re
+        .captures(s.trim())
+        .ok_or_else(|| format!("invalid size `{s}`, expected e.g. `65536`, `512M` or `4G`"))?;
+    let num: f64 = caps[1].parse().map_err(|_| format!("invalid size `{s}`"))?;
+    let unit: u64 = match &caps[2] {
+        "K" => 1 << 10,
+        "M" => 1 << 20,
+        "G" => 1 << 30,
+        "T" => 1 << 40,
+        _ => 1,
+    };
+    Ok((num * unit as f64) as u64)
+}
+
+/// The variables that templates like `{{task_id[..8]}}` can use.
+pub const TEMPLATE_VARIABLES: [&str; 2] = ["task_id", "user_id"];
+
+/// A template `{{name}}` or `{{name[low..high]}}`, with the name in group 1
+/// and the optional bounds of the slice in groups 4 and 5.
+pub fn template_regex() -> Regex {
+    Regex::new(r"\{\{(\w+)(\[((\d+)?\.\.(\d+)?)?\])?\}\}").unwrap()
+}
+
+/// Check the templates of `s` that are wrong whatever the task: unknown
+/// variables and slices whose start is past their end.
+pub fn check_template(s: &str) -> Result<(), String> {
+    for caps in template_regex().captures_iter(s) {
+        let name = &caps[1];
+        if !TEMPLATE_VARIABLES.contains(&name) {
+            return Err(format!(
+                "unknown template variable `{}`, expected `task_id` or `user_id`",
+                &caps[0]
+            ));
+        }
+        let bound = |i| caps.get(i).map(|m| m.as_str().parse::<usize>());
+        if let (Some(Ok(low)), Some(Ok(high))) = (bound(4), bound(5)) {
+            if low > high {
+                return Err(format!("the slice of `{}` ends before it starts", &caps[0]));
+            }
+        }
+    }
+    Ok(())
+}
+
+// longer durations are surely mistakes, and could overflow deadlines
+const MAX_DURATION: Duration = Duration::from_secs(100 * 365 * 24 * 60 * 60);
+
+/// Parse durations like `500ms`, `30s`, `2h` or `1h30m`, up to 100 years.
+pub fn parse_duration(s: &str) -> Result<Duration, String> {
+    let re = Regex::new(r"^(\d+)(ms|s|m|h|d)").unwrap();
+    let mut rest = s.trim();
+    if rest.is_empty() {
+        return Err("empty duration".to_string());
+    }
+    let mut duration = Duration::ZERO;
+    while !rest.is_empty() {
+        let caps = match re.captures(rest) {
+            Some(caps) => caps,
+            None => {
+                return Err(format!(
+                    "invalid duration `{s}`, expected e.g. `500ms`, `30s`, `2h` or `1h30m`"
+                ))
+            }
+        };
+        let too_long = || format!("duration `{s}` is longer than 100 years");
+        let num: u64 = caps[1].parse().map_err(|_| too_long())?;
+        let part = match &caps[2] {
+            "ms" => Some(Duration::from_millis(num)),
+            "s" => Some(Duration::from_secs(num)),
+            "m" => num.checked_mul(60).map(Duration::from_secs),
+            "h" => num.checked_mul(60 * 60).map(Duration::from_secs),
+            _ => num.checked_mul(60 * 60 * 24).map(Duration::from_secs),
+        };
+        duration = part
+            .and_then(|part| duration.checked_add(part))
+            .filter(|duration| *duration <= MAX_DURATION)
+            .ok_or_else(too_long)?;
+        rest = &rest[caps[0].len()..];
     }
+    Ok(duration)
 }
 
 #[derive(Clone)]
@@ -41,81 +531,745 @@ pub struct RoleSpec {
     pub min_num: Option<i64>,
     pub steps: Vec<StepSpec>,
     pub workdir: Option<String>,
+    /// default of `log_output` for the steps of this role
+    pub log_output: Option<bool>,
+    /// how long processes get to exit after the signal of `process_stop`, or
+    /// after SIGTERM at the end of a task, before they are killed
+    pub grace_period: Option<String>,
+    /// default of `shell` for the steps of this role
+    pub shell: Option<String>,
+    /// environment variables of the processes of this role, over those of the
+    /// protocol
+    pub env: BTreeMap<String, String>,
+    /// start processes without the environment of the operator, except the
+    /// variables in `env_allow`
+    pub env_clear: Option<bool>,
+    /// names of variables kept by `env_clear`, a trailing `*` matches a prefix
+    pub env_allow: Option<Vec<String>>,
+    /// set `COLINK_JWT` for the processes
+    pub pass_jwt: Option<bool>,
+    /// names of environment variables whose values are hidden in logs, the
+    /// task status and errors
+    pub secrets: Option<Vec<String>>,
+    /// default limits of the processes of this role
+    pub limits: LimitsSpec,
+    /// default of `sandbox` for the steps of this role
+    pub sandbox: Option<bool>,
+    /// default of `sandbox_network` for the steps of this role
+    pub sandbox_network: Option<bool>,
 }
 
 impl RoleSpec {
-    pub fn new(name: String, value: &Value) -> Result<RoleSpec, Box<dyn std::error::Error>> {
-        let max_num = value.get("max_num").map(|num| num.as_integer().unwrap());
-        let min_num = value.get("min_num").map(|num| num.as_integer().unwrap());
-        let playbook = value.get("playbook").unwrap();
-        let workdir = playbook
-            .get("workdir")
-            .map(|dir_get| dir_get.as_str().unwrap().to_string());
+    pub fn new(
+        path: &str,
+        name: String,
+        value: &Value,
+        errors: &mut Vec<SpecError>,
+    ) -> Option<RoleSpec> {
+        let max_num = optional_field(path, value, "max_num", FieldType::Integer, errors)
+            .and_then(|num| num.as_integer());
+        let min_num = optional_field(path, value, "min_num", FieldType::Integer, errors)
+            .and_then(|num| num.as_integer());
+        let playbook = required_field(path, value, "playbook", FieldType::Table, errors)?;
+        let playbook_path = format!("{path}.playbook");
+        let workdir = optional_field(
+            &playbook_path,
+            playbook,
+            "workdir",
+            FieldType::String,
+            errors,
+        )
+        .map(|dir_get| dir_get.as_str().unwrap().to_string());
+        let log_output = optional_field(
+            &playbook_path,
+            playbook,
+            "log_output",
+            FieldType::Boolean,
+            errors,
+        )
+        .and_then(|log_output| log_output.as_bool());
+        let grace_period = optional_field(
+            &playbook_path,
+            playbook,
+            "grace_period",
+            FieldType::String,
+            errors,
+        )
+        .map(|grace_period| grace_period.as_str().unwrap().to_string());
+        let shell = optional_field(&playbook_path, playbook, "shell", FieldType::String, errors)
+            .map(|shell| shell.as_str().unwrap().to_string());
+        let env = env_field(&playbook_path, playbook, errors);
+        let env_clear = optional_field(
+            &playbook_path,
+            playbook,
+            "env_clear",
+            FieldType::Boolean,
+            errors,
+        )
+        .and_then(|env_clear| env_clear.as_bool());
+        let env_allow = string_array_field(&playbook_path, playbook, "env_allow", errors);
+        let pass_jwt = optional_field(
+            &playbook_path,
+            playbook,
+            "pass_jwt",
+            FieldType::Boolean,
+            errors,
+        )
+        .and_then(|pass_jwt| pass_jwt.as_bool());
+        let secrets = string_array_field(&playbook_path, playbook, "secrets", errors);
+        let limits = limits_field(&playbook_path, playbook, errors);
+        let sandbox = optional_field(
+            &playbook_path,
+            playbook,
+            "sandbox",
+            FieldType::Boolean,
+            errors,
+        )
+        .and_then(|sandbox| sandbox.as_bool());
+        let sandbox_network = optional_field(
+            &playbook_path,
+            playbook,
+            "sandbox_network",
+            FieldType::Boolean,
+            errors,
+        )
+        .and_then(|sandbox_network| sandbox_network.as_bool());
+        let steps_value =
+            required_field(&playbook_path, playbook, "steps", FieldType::Array, errors)?;
         let mut steps: Vec<StepSpec> = Vec::new();
-        for step_value in playbook
-            .get("steps")
-            .and_then(|steps| steps.as_array())
-            .unwrap()
-        {
-            steps.push(StepSpec::new(step_value).unwrap());
-        }
-        Ok(RoleSpec {
+        let mut all_steps_valid = true;
+        for (i, step_value) in steps_value.as_array().unwrap().iter().enumerate() {
+            let step_path = format!("{playbook_path}.steps[{i}]");
+            match StepSpec::new(&step_path, step_value, errors) {
+                Some(step) => steps.push(step),
+                None => all_steps_valid = false,
+            }
+        }
+        if !all_steps_valid {
+            return None;
+        }
+        Some(RoleSpec {
             name,
             max_num,
             min_num,
             steps,
             workdir,
+            log_output,
+            grace_period,
+            shell,
+            env,
+            env_clear,
+            env_allow,
+            pass_jwt,
+            secrets,
+            limits,
+            sandbox,
+            sandbox_network,
         })
     }
 }
 
 pub struct ProtocolSpec {
+    /// key of the protocol table in the config, the root of its TOML paths
+    pub key: String,
     pub protocol_name: String,
     pub workdir: String,
     pub roles: Vec<RoleSpec>,
+    /// environment variables of the processes of every role
+    pub env: BTreeMap<String, String>,
 }
 
 impl ProtocolSpec {
-    pub fn new(value: &Value) -> Result<ProtocolSpec, Box<dyn std::error::Error>> {
-        let name = value.get("name").unwrap().as_str().unwrap();
-        let workdir = value.get("workdir").unwrap().as_str().unwrap();
+    pub fn new(path: &str, value: &Value, errors: &mut Vec<SpecError>) -> Option<ProtocolSpec> {
+        let name = required_field(path, value, "name", FieldType::String, errors);
+        let workdir = required_field(path, value, "workdir", FieldType::String, errors);
+        let roles_table = required_field(path, value, "roles", FieldType::Table, errors);
+        let env = env_field(path, value, errors);
         let mut roles: Vec<RoleSpec> = Vec::new();
-        let roles_table = value
-            .get("roles")
-            .and_then(|roles| roles.as_table())
-            .unwrap();
-        for (name, value) in roles_table {
-            roles.push(RoleSpec::new(name.clone(), value).unwrap());
-        }
-        Ok(ProtocolSpec {
-            protocol_name: name.to_string(),
-            workdir: workdir.to_string(),
+        let mut all_roles_valid = true;
+        if let Some(roles_table) = roles_table {
+            for (name, value) in roles_table.as_table().unwrap() {
+                let roles_path = format!("{path}.roles");
+                if !check_type(&roles_path, name, value, FieldType::Table, errors) {
+                    all_roles_valid = false;
+                    continue;
+                }
+                let role_path = format!("{roles_path}.{name}");
+                match RoleSpec::new(&role_path, name.clone(), value, errors) {
+                    Some(role) => roles.push(role),
+                    None => all_roles_valid = false,
+                }
+            }
+        }
+        if !all_roles_valid {
+            return None;
+        }
+        Some(ProtocolSpec {
+            key: path.to_string(),
+            protocol_name: name?.as_str().unwrap().to_string(),
+            workdir: workdir?.as_str().unwrap().to_string(),
             roles,
+            env,
         })
     }
+
+    pub fn role_path(&self, role_name: &str) -> String {
+        format!("{}.roles.{}", self.key, role_name)
+    }
+
+    pub fn step_path(&self, role_name: &str, step_index: usize) -> String {
+        format!(
+            "{}.playbook.steps[{}]",
+            self.role_path(role_name),
+            step_index
+        )
+    }
 }
 
-type PackageSpec = Vec<ProtocolSpec>;
+pub type PackageSpec = Vec<ProtocolSpec>;
 
-pub fn parse_spec_from_toml(toml_str: &str) -> Result<PackageSpec, Box<dyn std::error::Error>> {
-    let root_node = toml_str.parse::<Value>()?;
+pub fn parse_spec_from_toml(toml_str: &str) -> Result<PackageSpec, SpecErrors> {
+    let root_node = match toml_str.parse::<Value>() {
+        Ok(root_node) => root_node,
+        Err(e) => return Err(SpecErrors(vec![SpecError::from_syntax_error(&e)])),
+    };
     let root_table = root_node.as_table().unwrap();
     let mut package_spec: PackageSpec = Vec::new();
+    let mut errors: Vec<SpecError> = Vec::new();
     for (name, value) in root_table {
         if value.as_table().is_some() {
             if name == "package" {
                 let use_playbook = match value.get("use_playbook") {
-                    Some(val) => val.as_bool().unwrap(),
+                    // a mistyped flag is already reported by `check_type`
+                    Some(val) => {
+                        !check_type(name, "use_playbook", val, FieldType::Boolean, &mut errors)
+                            || val.as_bool().unwrap()
+                    }
                     None => false,
                 };
                 if !use_playbook {
-                    return Err("use_playbook need to be defined and set to true to activate playbook module".into());
+                    errors.push(SpecError::new(
+                        name,
+                        Some("use_playbook"),
+                        SpecErrorKind::Invalid("use_playbook need to be defined and set to true to activate playbook module".to_string()),
+                    ));
                 }
                 continue;
-            } else {
-                package_spec.push(ProtocolSpec::new(value)?);
+            } else if let Some(protocol_spec) = ProtocolSpec::new(name, value, &mut errors) {
+                package_spec.push(protocol_spec);
             }
         }
     }
+    if !errors.is_empty() {
+        let source_map = SourceMap::new(toml_str);
+        for error in &mut errors {
+            source_map.locate(error);
+        }
+        return Err(SpecErrors(errors));
+    }
     Ok(package_spec)
 }
+
+#[derive(Clone, Copy)]
+pub enum FieldType {
+    String,
+    Integer,
+    Boolean,
+    Array,
+    Table,
+    /// `check_exit_code`, an integer, a string or an array of them
+    ExitCodes,
+}
+
+impl FieldType {
+    pub fn name(&self) -> &'static str {
+        match self {
+            FieldType::String => "string",
+            FieldType::Integer => "integer",
+            FieldType::Boolean => "boolean",
+            FieldType::Array => "array",
+            FieldType::Table => "table",
+            FieldType::ExitCodes => "integer, string or array",
+        }
+    }
+
+    fn matches(&self, value: &Value) -> bool {
+        matches!(
+            (self, value),
+            (FieldType::String, Value::String(_))
+                | (FieldType::Integer, Value::Integer(_))
+                | (FieldType::Boolean, Value::Boolean(_))
+                | (FieldType::Array, Value::Array(_))
+                | (FieldType::Table, Value::Table(_))
+                | (
+                    FieldType::ExitCodes,
+                    Value::Integer(_) | Value::String(_) | Value::Array(_)
+                )
+        )
+    }
+}
+
+fn check_type(
+    path: &str,
+    field: &str,
+    value: &Value,
+    field_type: FieldType,
+    errors: &mut Vec<SpecError>,
+) -> bool {
+    if field_type.matches(value) {
+        return true;
+    }
+    errors.push(SpecError::new(
+        path,
+        Some(field),
+        SpecErrorKind::WrongType {
+            expected: field_type.name(),
+            found: value.type_str(),
+        },
+    ));
+    false
+}
+
+fn check_fields(
+    path: &str,
+    table: &toml::value::Table,
+    fields: &[(&str, FieldType)],
+    errors: &mut Vec<SpecError>,
+) {
+    for (key, field_value) in table {
+        match fields.iter().find(|(name, _)| name == key) {
+            Some((_, field_type)) => {
+                check_type(path, key, field_value, *field_type, errors);
+            }
+            None => errors.push(SpecError::new(path, Some(key), SpecErrorKind::UnknownField)),
+        }
+    }
+}
+
+fn optional_field<'a>(
+    path: &str,
+    value: &'a Value,
+    field: &str,
+    field_type: FieldType,
+    errors: &mut Vec<SpecError>,
+) -> Option<&'a Value> {
+    let field_value = value.get(field)?;
+    if check_type(path, field, field_value, field_type, errors) {
+        Some(field_value)
+    } else {
+        None
+    }
+}
+
+// The `env` table of `value`, variables that are not strings are reported and
+// left out.
+fn env_field(path: &str, value: &Value, errors: &mut Vec<SpecError>) -> BTreeMap<String, String> {
+    let mut env = BTreeMap::new();
+    if let Some(table) = optional_field(path, value, "env", FieldType::Table, errors) {
+        let env_path = format!("{path}.env");
+        for (name, value) in table.as_table().unwrap() {
+            if check_type(&env_path, name, value, FieldType::String, errors) {
+                env.insert(name.clone(), value.as_str().unwrap().to_string());
+            }
+        }
+    }
+    env
+}
+
+// The `limits` table of `value`, empty if it is missing or has mistakes.
+fn limits_field(path: &str, value: &Value, errors: &mut Vec<SpecError>) -> LimitsSpec {
+    let limits = match optional_field(path, value, "limits", FieldType::Table, errors) {
+        Some(limits) => limits,
+        None => return LimitsSpec::default(),
+    };
+    let limits_path = format!("{path}.limits");
+    let errors_before = errors.len();
+    check_fields(
+        &limits_path,
+        limits.as_table().unwrap(),
+        LIMITS_FIELDS,
+        errors,
+    );
+    if errors.len() > errors_before {
+        return LimitsSpec::default();
+    }
+    match limits.clone().try_into::<LimitsSpec>() {
+        Ok(limits) => limits,
+        Err(e) => {
+            errors.push(SpecError::new(
+                &limits_path,
+                None,
+                SpecErrorKind::Invalid(e.to_string()),
+            ));
+            LimitsSpec::default()
+        }
+    }
+}
+
+// An array of strings, the items that are not strings are reported and left
+// out.
+fn string_array_field(
+    path: &str,
+    value: &Value,
+    field: &str,
+    errors: &mut Vec<SpecError>,
+) -> Option<Vec<String>> {
+    let array = optional_field(path, value, field, FieldType::Array, errors)?;
+    let mut strings = Vec::new();
+    for (i, item) in array.as_array().unwrap().iter().enumerate() {
+        if check_type(
+            path,
+            &format!("{field}[{i}]"),
+            item,
+            FieldType::String,
+            errors,
+        ) {
+            strings.push(item.as_str().unwrap().to_string());
+        }
+    }
+    Some(strings)
+}
+
+fn required_field<'a>(
+    path: &str,
+    value: &'a Value,
+    field: &str,
+    field_type: FieldType,
+    errors: &mut Vec<SpecError>,
+) -> Option<&'a Value> {
+    if value.get(field).is_none() {
+        errors.push(SpecError::new(
+            path,
+            Some(field),
+            SpecErrorKind::MissingField {
+                expected: field_type.name(),
+            },
+        ));
+        return None;
+    }
+    optional_field(path, value, field, field_type, errors)
+}
+
+#[derive(Debug, Clone)]
+pub enum SpecErrorKind {
+    Syntax(String),
+    MissingField {
+        expected: &'static str,
+    },
+    WrongType {
+        expected: &'static str,
+        found: &'static str,
+    },
+    UnknownField,
+    Invalid(String),
+    /// suspicious but not fatal, e.g. a variable that is sent but never received
+    Warning(String),
+}
+
+/// A problem found in the playbook config, pointing at the TOML path
+/// (e.g. `fedtree.roles.server.playbook.steps[3]`) and field it comes from.
+#[derive(Debug, Clone)]
+pub struct SpecError {
+    pub path: String,
+    pub field: Option<String>,
+    /// 1-based (line, column) in the config file, when it can be found
+    pub line_col: Option<(usize, usize)>,
+    pub kind: SpecErrorKind,
+}
+
+impl SpecError {
+    pub fn new(path: &str, field: Option<&str>, kind: SpecErrorKind) -> SpecError {
+        SpecError {
+            path: path.to_string(),
+            field: field.map(|field| field.to_string()),
+            line_col: None,
+            kind,
+        }
+    }
+
+    pub fn is_warning(&self) -> bool {
+        matches!(self.kind, SpecErrorKind::Warning(_))
+    }
+
+    fn from_syntax_error(e: &toml::de::Error) -> SpecError {
+        let message = e.to_string();
+        // the position is reported separately, so drop toml's own " at line N" suffix
+        let message = match message.rfind(" at line ") {
+            Some(pos) => message[..pos].to_string(),
+            None => message,
+        };
+        SpecError {
+            path: String::new(),
+            field: None,
+            line_col: e.line_col().map(|(line, col)| (line + 1, col + 1)),
+            kind: SpecErrorKind::Syntax(message),
+        }
+    }
+}
+
+impl std::fmt::Display for SpecError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.is_warning() {
+            write!(f, "warning: ")?;
+        }
+        if let Some((line, col)) = self.line_col {
+            write!(f, "line {line}, column {col}: ")?;
+        }
+        match (&self.path[..], &self.field) {
+            ("", None) => {}
+            ("", Some(field)) => write!(f, "{field}: ")?,
+            (path, None) => write!(f, "{path}: ")?,
+            (path, Some(field)) => write!(f, "{path}.{field}: ")?,
+        }
+        match &self.kind {
+            SpecErrorKind::Syntax(message) => write!(f, "invalid TOML: {message}"),
+            SpecErrorKind::MissingField { expected } => {
+                write!(f, "missing required field, expected {expected}")
+            }
+            SpecErrorKind::WrongType { expected, found } => {
+                write!(f, "expected {expected}, found {found}")
+            }
+            SpecErrorKind::UnknownField => write!(f, "unknown field"),
+            SpecErrorKind::Invalid(message) => write!(f, "{message}"),
+            SpecErrorKind::Warning(message) => write!(f, "{message}"),
+        }
+    }
+}
+
+impl std::error::Error for SpecError {}
+
+/// All the problems found in one config file.
+#[derive(Debug)]
+pub struct SpecErrors(pub Vec<SpecError>);
+
+impl std::fmt::Display for SpecErrors {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for (i, error) in self.0.iter().enumerate() {
+            if i > 0 {
+                writeln!(f)?;
+            }
+            write!(f, "{error}")?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for SpecErrors {}
+
+/// Best-effort map from TOML paths to the position where they are written,
+/// built by scanning table headers and `key = value` lines of the source.
+pub struct SourceMap {
+    positions: HashMap<String, (usize, usize)>,
+}
+
+impl SourceMap {
+    pub fn new(toml_str: &str) -> SourceMap {
+        let mut positions: HashMap<String, (usize, usize)> = HashMap::new();
+        let mut array_counters: HashMap<String, usize> = HashMap::new();
+        let mut current_table = String::new();
+        for (line_no, line) in toml_str.lines().enumerate() {
+            let trimmed = line.trim_start();
+            let col = line.len() - trimmed.len() + 1;
+            let position = (line_no + 1, col);
+            if trimmed.is_empty() || trimmed.starts_with('#') {
+                continue;
+            }
+            if let Some(header) = trimmed.strip_prefix("[[") {
+                let header = normalize_key(header.split("]]").next().unwrap_or_default());
+                let resolved = Self::resolve(&array_counters, &header);
+                let index = array_counters.entry(header).or_insert(0);
+                current_table = format!("{resolved}[{index}]");
+                *index += 1;
+                positions.entry(current_table.clone()).or_insert(position);
+            } else if let Some(header) = trimmed.strip_prefix('[') {
+                let header = normalize_key(header.split(']').next().unwrap_or_default());
+                current_table = Self::resolve(&array_counters, &header);
+                positions.entry(current_table.clone()).or_insert(position);
+            } else if let Some((key, _)) = trimmed.split_once('=') {
+                let key = normalize_key(key);
+                let path = if current_table.is_empty() {
+                    key
+                } else {
+                    format!("{current_table}.{key}")
+                };
+                positions.entry(path).or_insert(position);
+            }
+        }
+        SourceMap { positions }
+    }
+
+    // rewrite every prefix of `header` naming an array of tables to its latest element
+    fn resolve(array_counters: &HashMap<String, usize>, header: &str) -> String {
+        let mut raw = String::new();
+        let mut resolved = String::new();
+        let segments: Vec<&str> = header.split('.').collect();
+        for (i, segment) in segments.iter().enumerate() {
+            if i > 0 {
+                raw.push('.');
+                resolved.push('.');
+            }
+            raw.push_str(segment);
+            resolved.push_str(segment);
+            if i + 1 < segments.len() {
+                if let Some(count) = array_counters.get(&raw) {
+                    resolved.push_str(&format!("[{}]", count.saturating_sub(1)));
+                }
+            }
+        }
+        resolved
+    }
+
+    /// Fill in `error.line_col` with the closest position known for its path.
+    pub fn locate(&self, error: &mut SpecError) {
+        if error.line_col.is_some() {
+            return;
+        }
+        if let Some(field) = &error.field {
+            let full_path = if error.path.is_empty() {
+                field.clone()
+            } else {
+                format!("{}.{}", error.path, field)
+            };
+            if let Some(position) = self.positions.get(&full_path) {
+                error.line_col = Some(*position);
+                return;
+            }
+        }
+        let mut path = error.path.as_str();
+        while !path.is_empty() {
+            if let Some(position) = self.positions.get(path) {
+                error.line_col = Some(*position);
+                return;
+            }
+            path = match path.rfind(['.', '[']) {
+                Some(pos) => &path[..pos],
+                None => "",
+            };
+        }
+    }
+}
+
+fn normalize_key(key: &str) -> String {
+    key.split('.')
+        .map(|segment| segment.trim().trim_matches('"').trim_matches('\''))
+        .collect::<Vec<&str>>()
+        .join(".")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn locate() {
+        let toml_str = r#"[package]
+use_playbook = true
+
+[t]
+name = "t"
+[t.roles.a.playbook]
+  [[t.roles.a.playbook.steps]]
+    process = "true"
+  [[t.roles.a.playbook.steps]]
+    process_wait = "p"
+    timeout = "1x"
+"#;
+        let source_map = SourceMap::new(toml_str);
+        let locate = |path: &str, field: Option<&str>| {
+            let mut error = SpecError::new(path, field, SpecErrorKind::UnknownField);
+            source_map.locate(&mut error);
+            error.line_col
+        };
+        assert_eq!(locate("t", Some("name")), Some((5, 1)));
+        assert_eq!(
+            locate("t.roles.a.playbook.steps[1]", Some("timeout")),
+            Some((11, 5))
+        );
+        assert_eq!(
+            locate("t.roles.a.playbook.steps[0]", Some("process")),
+            Some((8, 5))
+        );
+        // a missing field points at its table
+        assert_eq!(
+            locate("t.roles.a.playbook.steps[0]", Some("step_name")),
+            Some((7, 3))
+        );
+        assert_eq!(locate("t", Some("workdir")), Some((4, 1)));
+        assert_eq!(locate("other", None), None);
+    }
+
+    #[test]
+    fn durations() {
+        assert_eq!(parse_duration("500ms"), Ok(Duration::from_millis(500)));
+        assert_eq!(parse_duration(" 30s "), Ok(Duration::from_secs(30)));
+        assert_eq!(parse_duration("1h30m"), Ok(Duration::from_secs(5400)));
+        assert_eq!(parse_duration("2d"), Ok(Duration::from_secs(172800)));
+        assert!(parse_duration("").is_err());
+        assert!(parse_duration("30").is_err());
+        assert!(parse_duration("1h 30m").is_err());
+        assert!(parse_duration("-1s").is_err());
+        assert!(parse_duration("36500d").is_ok());
+        assert!(parse_duration("36501d").is_err());
+        assert!(parse_duration("18446744073709551615h").is_err());
+        assert!(parse_duration("99999999999999999999s").is_err());
+    }
+
+    #[test]
+    fn check_exit_code() {
+        let check = |toml_str: &str| -> CheckExitCode {
+            toml::from_str::<StepSpec>(&format!("check_exit_code = {toml_str}"))
+                .unwrap()
+                .check_exit_code
+                .unwrap()
+        };
+        assert_eq!(check("0").matches(0), Ok(true));
+        assert_eq!(check("0").matches(1), Ok(false));
+        assert_eq!(check(r#""2-5""#).matches(5), Ok(true));
+        assert_eq!(check(r#""2-5""#).matches(6), Ok(false));
+        assert_eq!(check(r#""!0""#).matches(0), Ok(false));
+        assert_eq!(check(r#""!0""#).matches(-1), Ok(true));
+        let list = check(r#"["1-10", "!5", "20"]"#);
+        assert_eq!(list.matches(3), Ok(true));
+        assert_eq!(list.matches(5), Ok(false));
+        assert_eq!(list.matches(20), Ok(true));
+        assert_eq!(list.matches(0), Ok(false));
+        assert!(check("[]").matches(0).is_err());
+        assert!(check(r#""5-2""#).matches(3).is_err());
+        assert!(check(r#""abc""#).matches(0).is_err());
+    }
+
+    #[test]
+    fn sizes() {
+        assert_eq!(parse_size("65536"), Ok(65536));
+        assert_eq!(parse_size("512K"), Ok(512 << 10));
+        assert_eq!(parse_size("4G"), Ok(4 << 30));
+        assert_eq!(parse_size("1.5G"), Ok(3 << 29));
+        assert_eq!(parse_size("2MiB"), Ok(2 << 20));
+        assert_eq!(parse_size("1 T"), Ok(1 << 40));
+        assert!(parse_size("").is_err());
+        assert!(parse_size("4X").is_err());
+        assert!(parse_size("-1").is_err());
+    }
+
+    #[test]
+    fn shell_commands() {
+        let argv = |shell, command| shell_argv(shell, command).map(|argv| argv.join("|"));
+        assert_eq!(
+            argv(None, "echo $HOME"),
+            Ok("bash|-c|echo $HOME".to_string())
+        );
+        assert_eq!(argv(Some("sh"), "true"), Ok("sh|-c|true".to_string()));
+        assert_eq!(
+            argv(Some(" bash -euo pipefail "), "true"),
+            Ok("bash|-euo|pipefail|-c|true".to_string())
+        );
+        assert_eq!(
+            argv(Some("bash -c"), "true"),
+            Ok("bash|-c|true".to_string())
+        );
+        assert_eq!(argv(Some("none"), " sleep  1 "), Ok("sleep|1".to_string()));
+        assert!(argv(Some("none"), " ").is_err());
+        assert!(argv(Some(""), "true").is_err());
+        assert!(argv(Some("  "), "true").is_err());
+    }
+}
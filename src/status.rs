@@ -0,0 +1,70 @@
+use crate::outcome::ProcessOutcome;
+use serde::Serialize;
+use std::collections::BTreeMap;
+
+/// Key of the entry a participant keeps its `TaskStatus` in.
+pub fn status_entry_key(task_id: &str, role: &str) -> String {
+    format!("_playbook:status:{task_id}:{role}")
+}
+
+/// Milliseconds since the Unix epoch.
+pub fn unix_millis() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map_or(0, |duration| duration.as_millis() as u64)
+}
+
+#[derive(Serialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum TaskState {
+    Running,
+    Succeeded,
+    Failed,
+}
+
+#[derive(Serialize)]
+pub struct StepStatus {
+    pub index: usize,
+    pub step_name: Option<String>,
+    pub action: &'static str,
+    pub started_at: u64,
+}
+
+/// Progress of one participant in a task, published as JSON to the entry
+/// `_playbook:status:{task_id}:{role}` whenever a step starts and when the
+/// task ends.
+#[derive(Serialize)]
+pub struct TaskStatus {
+    pub protocol: String,
+    pub role: String,
+    pub user_id: String,
+    pub task_id: String,
+    pub state: TaskState,
+    pub started_at: u64,
+    pub finished_at: Option<u64>,
+    // the step running now, or the last one that ran
+    pub step: Option<StepStatus>,
+    // how the processes joined or killed so far ended, by step_name
+    pub process_outcomes: BTreeMap<String, ProcessOutcome>,
+    // processes still running when the task ended, terminated by the playbook
+    pub terminated_processes: Vec<String>,
+    pub error: Option<String>,
+}
+
+impl TaskStatus {
+    pub fn new(protocol: &str, role: &str, user_id: &str, task_id: &str) -> TaskStatus {
+        TaskStatus {
+            protocol: protocol.to_string(),
+            role: role.to_string(),
+            user_id: user_id.to_string(),
+            task_id: task_id.to_string(),
+            state: TaskState::Running,
+            started_at: unix_millis(),
+            finished_at: None,
+            step: None,
+            process_outcomes: BTreeMap::new(),
+            terminated_processes: Vec::new(),
+            error: None,
+        }
+    }
+}
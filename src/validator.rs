@@ -0,0 +1,1087 @@
+use crate::limits::parse_cpus;
+use crate::pairing::check_variable_pairing;
+use crate::spec_parser::{
+    check_template, parse_duration, parse_signal, parse_size, shell_argv, LimitsSpec, PackageSpec,
+    ProtocolSpec, RetryOn, RetrySpec, RoleSpec, SpecError, SpecErrorKind, StepSpec,
+};
+use regex::Regex;
+use std::collections::{BTreeMap, HashMap, HashSet};
+
+// actions a step can perform, a step may only use one of them
+// (`process` or `argv` is allowed to be combined with `process_wait`, `process_kill` or
+// `process_stop`)
+const STEP_ACTIONS: &[&str] = &[
+    "process_wait",
+    "process_kill",
+    "process_stop",
+    "send_variable",
+    "recv_variable",
+    "create_entry",
+    "update_entry",
+    "delete_entry",
+    "read_entry",
+    "read_or_wait_entry",
+];
+
+fn set_actions(step: &StepSpec) -> Vec<&'static str> {
+    let fields = [
+        step.process_wait.is_some(),
+        step.process_kill.is_some(),
+        step.process_stop.is_some(),
+        step.send_variable.is_some(),
+        step.recv_variable.is_some(),
+        step.create_entry.is_some(),
+        step.update_entry.is_some(),
+        step.delete_entry.is_some(),
+        step.read_entry.is_some(),
+        step.read_or_wait_entry.is_some(),
+    ];
+    STEP_ACTIONS
+        .iter()
+        .zip(fields)
+        .filter(|(_, is_set)| *is_set)
+        .map(|(action, _)| *action)
+        .collect()
+}
+
+fn invalid(errors: &mut Vec<SpecError>, path: &str, field: Option<&str>, message: String) {
+    errors.push(SpecError::new(path, field, SpecErrorKind::Invalid(message)));
+}
+
+/// Check the playbooks for mistakes the interpreter would otherwise only hit
+/// while the protocol is running. Problems that may be intended are returned
+/// as warnings (see `SpecError::is_warning`).
+pub fn validate_package(package_spec: &PackageSpec) -> Vec<SpecError> {
+    let mut errors: Vec<SpecError> = Vec::new();
+    for protocol_spec in package_spec {
+        validate_env(&protocol_spec.env, &protocol_spec.key, &mut errors);
+        if let Err(e) = check_template(&protocol_spec.workdir) {
+            invalid(&mut errors, &protocol_spec.key, Some("workdir"), e);
+        }
+        for role_spec in &protocol_spec.roles {
+            validate_role(protocol_spec, role_spec, &mut errors);
+        }
+        errors.extend(check_variable_pairing(protocol_spec));
+    }
+    errors
+}
+
+fn validate_role(protocol_spec: &ProtocolSpec, role_spec: &RoleSpec, errors: &mut Vec<SpecError>) {
+    let role_names: HashSet<&str> = protocol_spec
+        .roles
+        .iter()
+        .map(|role| role.name.as_str())
+        .collect();
+    let playbook_path = format!("{}.playbook", protocol_spec.role_path(&role_spec.name));
+    if let Some(Err(e)) = role_spec.workdir.as_deref().map(check_template) {
+        invalid(errors, &playbook_path, Some("workdir"), e);
+    }
+    if let Some(grace_period) = &role_spec.grace_period {
+        if let Err(e) = parse_duration(grace_period) {
+            invalid(errors, &playbook_path, Some("grace_period"), e);
+        }
+    }
+    if let Some(shell) = &role_spec.shell {
+        validate_shell(shell, &playbook_path, errors);
+    }
+    validate_env(&role_spec.env, &playbook_path, errors);
+    validate_env_policy(role_spec, &playbook_path, errors);
+    validate_limits(&role_spec.limits, &playbook_path, errors);
+    validate_sandbox_network(
+        role_spec.sandbox,
+        role_spec.sandbox_network,
+        &playbook_path,
+        errors,
+    );
+    // processes that may still be running before the current step, with the
+    // index of the step starting them
+    let mut started: HashMap<&str, usize> = HashMap::new();
+    for (i, step) in role_spec.steps.iter().enumerate() {
+        let path = protocol_spec.step_path(&role_spec.name, i);
+        let actions = set_actions(step);
+        validate_templates(step, &path, errors);
+        if actions.len() > 1 {
+            invalid(
+                errors,
+                &path,
+                Some(actions[1]),
+                format!(
+                    "`{}` and `{}` cannot be set in the same step",
+                    actions[0], actions[1]
+                ),
+            );
+        } else if step.starts_process() && actions.len() == 1 && !actions[0].starts_with("process_")
+        {
+            let field = if step.argv.is_some() {
+                "argv"
+            } else {
+                "process"
+            };
+            invalid(
+                errors,
+                &path,
+                Some(field),
+                format!("`{field}` cannot be set together with `{}`", actions[0]),
+            );
+        }
+        if step.process.is_some() && step.argv.is_some() {
+            invalid(
+                errors,
+                &path,
+                Some("argv"),
+                "`process` and `argv` cannot be set in the same step".to_string(),
+            );
+        }
+        if step.argv.as_ref().is_some_and(|argv| argv.is_empty()) {
+            invalid(
+                errors,
+                &path,
+                Some("argv"),
+                "`argv` should name at least the program to run".to_string(),
+            );
+        }
+        if let Some(shell) = &step.shell {
+            validate_shell(shell, &path, errors);
+            if step.process.is_none() && step._if.is_none() {
+                errors.push(SpecError::new(
+                    &path,
+                    Some("shell"),
+                    SpecErrorKind::Warning(
+                        "`shell` only applies to `process` and `if`".to_string(),
+                    ),
+                ));
+            }
+        }
+        if step.stdin.is_some() && step.stdin_file.is_some() {
+            invalid(
+                errors,
+                &path,
+                Some("stdin"),
+                "`stdin` and `stdin_file` cannot be set in the same step".to_string(),
+            );
+        }
+        if !step.starts_process() {
+            for (field, value) in [("stdin", &step.stdin), ("stdin_file", &step.stdin_file)] {
+                if value.is_some() {
+                    errors.push(SpecError::new(
+                        &path,
+                        Some(field),
+                        SpecErrorKind::Warning(format!(
+                            "`{field}` only applies to `process` and `argv`"
+                        )),
+                    ));
+                }
+            }
+        }
+        if let Some(limits) = &step.limits {
+            validate_limits(limits, &path, errors);
+            if !step.starts_process() && step._if.is_none() {
+                errors.push(SpecError::new(
+                    &path,
+                    Some("limits"),
+                    SpecErrorKind::Warning(
+                        "`limits` only applies to `process`, `argv` and `if`".to_string(),
+                    ),
+                ));
+            }
+        }
+        if let Some(env) = &step.env {
+            validate_env(env, &path, errors);
+            if !step.starts_process() && step._if.is_none() {
+                errors.push(SpecError::new(
+                    &path,
+                    Some("env"),
+                    SpecErrorKind::Warning(
+                        "`env` only applies to `process`, `argv` and `if`".to_string(),
+                    ),
+                ));
+            }
+        }
+        for (field, value) in [
+            ("sandbox", step.sandbox),
+            ("sandbox_network", step.sandbox_network),
+        ] {
+            if value.is_some() && !step.starts_process() && step._if.is_none() {
+                errors.push(SpecError::new(
+                    &path,
+                    Some(field),
+                    SpecErrorKind::Warning(format!(
+                        "`{field}` only applies to `process`, `argv` and `if`"
+                    )),
+                ));
+            }
+        }
+        validate_sandbox_network(
+            step.sandbox.or(role_spec.sandbox),
+            step.sandbox_network,
+            &path,
+            errors,
+        );
+        if !step.starts_process() && actions.is_empty() {
+            invalid(
+                errors,
+                &path,
+                None,
+                "step does not define any action".to_string(),
+            );
+        }
+
+        if let Some(step_name) = &step.step_name {
+            if step_name.starts_with("__") {
+                invalid(
+                    errors,
+                    &path,
+                    Some("step_name"),
+                    "`step_name` cannot start with `__`".to_string(),
+                );
+            }
+        }
+        if step.starts_process() {
+            match &step.step_name {
+                Some(step_name) => {
+                    if let Some(&start_index) = started.get(step_name.as_str()) {
+                        invalid(
+                            errors,
+                            &path,
+                            Some("step_name"),
+                            format!("process `{step_name}` started by steps[{start_index}] may still be running, it should be waited, killed or stopped before its `step_name` is used again"),
+                        );
+                    }
+                    started.insert(step_name.as_str(), i);
+                }
+                None => {
+                    let field = if step.argv.is_some() {
+                        "argv"
+                    } else {
+                        "process"
+                    };
+                    invalid(
+                        errors,
+                        &path,
+                        Some(field),
+                        format!("`{field}` need `step_name`"),
+                    )
+                }
+            }
+        }
+        for (field, target) in [
+            ("process_kill", &step.process_kill),
+            ("process_stop", &step.process_stop),
+            ("process_wait", &step.process_wait),
+        ] {
+            if let Some(target) = target {
+                match started.get(target.as_str()) {
+                    Some(&start_index) => {
+                        let start_step = &role_spec.steps[start_index];
+                        // a conditional step may not start the process, so only a
+                        // step with the same condition can be sure it runs
+                        if start_index != i
+                            && start_step._if.is_some()
+                            && step._if != start_step._if
+                        {
+                            let message = format!("process `{target}` is only started if the `if` of steps[{start_index}] succeeds, this step fails when it does not, unless it has the same `if`");
+                            if step._if.is_none() {
+                                invalid(errors, &path, Some(field), message);
+                            } else {
+                                errors.push(SpecError::new(
+                                    &path,
+                                    Some(field),
+                                    SpecErrorKind::Warning(message),
+                                ));
+                            }
+                        }
+                        let has_output = |step: &StepSpec| {
+                            step.stdout_file.is_some() || step.stderr_file.is_some()
+                        };
+                        if start_index != i && has_output(start_step) && has_output(step) {
+                            errors.push(SpecError::new(
+                                &path,
+                                Some(field),
+                                SpecErrorKind::Warning(format!(
+                                    "the output of process `{target}` goes to the files set on the step starting it, `stdout_file` and `stderr_file` of this step are ignored"
+                                )),
+                            ));
+                        }
+                    }
+                    None => invalid(
+                        errors,
+                        &path,
+                        Some(field),
+                        format!("process `{target}` is not started by an earlier step"),
+                    ),
+                }
+                // a conditional step may not run, so the process may still need a wait later
+                if step._if.is_none() {
+                    started.remove(target.as_str());
+                }
+            }
+        }
+
+        if step.send_variable.is_some() {
+            if step.file.is_none() {
+                invalid(
+                    errors,
+                    &path,
+                    Some("send_variable"),
+                    "`send_variable` need `file`".to_string(),
+                );
+            }
+            if step.to_role.is_none() {
+                invalid(
+                    errors,
+                    &path,
+                    Some("send_variable"),
+                    "`send_variable` need `to_role`".to_string(),
+                );
+            }
+        }
+        if step.recv_variable.is_some() {
+            if step.from_role.is_none() {
+                invalid(
+                    errors,
+                    &path,
+                    Some("recv_variable"),
+                    "`recv_variable` need `from_role`".to_string(),
+                );
+            }
+            if step.role_index.is_none() {
+                invalid(
+                    errors,
+                    &path,
+                    Some("recv_variable"),
+                    "`recv_variable` need `index`".to_string(),
+                );
+            }
+        }
+        for (field, is_set) in [
+            ("create_entry", step.create_entry.is_some()),
+            ("update_entry", step.update_entry.is_some()),
+            ("read_entry", step.read_entry.is_some()),
+            ("read_or_wait_entry", step.read_or_wait_entry.is_some()),
+        ] {
+            if is_set && step.file.is_none() {
+                invalid(errors, &path, Some(field), format!("`{field}` need `file`"));
+            }
+        }
+        for (field, role) in [("to_role", &step.to_role), ("from_role", &step.from_role)] {
+            if let Some(role) = role {
+                if !role_names.contains(role.as_str()) {
+                    invalid(
+                        errors,
+                        &path,
+                        Some(field),
+                        format!(
+                            "role `{role}` is not defined in protocol `{}`",
+                            protocol_spec.protocol_name
+                        ),
+                    );
+                }
+            }
+        }
+        if let Some(timeout) = &step.timeout {
+            if let Err(e) = parse_duration(timeout) {
+                invalid(errors, &path, Some("timeout"), e);
+            }
+            if step.process_wait.is_none()
+                && step.recv_variable.is_none()
+                && step.read_or_wait_entry.is_none()
+            {
+                errors.push(SpecError::new(
+                    &path,
+                    Some("timeout"),
+                    SpecErrorKind::Warning(
+                        "`timeout` only applies to `process_wait`, `recv_variable` and `read_or_wait_entry`".to_string(),
+                    ),
+                ));
+            }
+        }
+        if let Some(signal) = &step.signal {
+            if let Err(e) = parse_signal(signal) {
+                invalid(errors, &path, Some("signal"), e);
+            }
+            if step.process_kill.is_none() && step.process_stop.is_none() {
+                errors.push(SpecError::new(
+                    &path,
+                    Some("signal"),
+                    SpecErrorKind::Warning(
+                        "`signal` only applies to `process_kill` and `process_stop`".to_string(),
+                    ),
+                ));
+            }
+        }
+        validate_outcome_checks(step, &path, errors);
+        if let Some(grace_period) = &step.grace_period {
+            if let Err(e) = parse_duration(grace_period) {
+                invalid(errors, &path, Some("grace_period"), e);
+            }
+            if step.process_stop.is_none() && step.process_kill.is_none() {
+                errors.push(SpecError::new(
+                    &path,
+                    Some("grace_period"),
+                    SpecErrorKind::Warning(
+                        "`grace_period` only applies to `process_stop` and `process_kill`"
+                            .to_string(),
+                    ),
+                ));
+            }
+        }
+        if let Some(retry) = &step.retry {
+            validate_retry(step, retry, &path, errors);
+        }
+        if let Some(index) = step.role_index {
+            if index < 0 {
+                invalid(
+                    errors,
+                    &path,
+                    Some("index"),
+                    "`index` cannot be negative".to_string(),
+                );
+            }
+        }
+    }
+}
+
+fn validate_outcome_checks(step: &StepSpec, path: &str, errors: &mut Vec<SpecError>) {
+    let checks: Vec<&str> = [
+        ("check_exit_code", step.check_exit_code.is_some()),
+        ("check_signal", step.check_signal.is_some()),
+        ("expect_success", step.expect_success.is_some()),
+    ]
+    .iter()
+    .filter(|(_, is_set)| *is_set)
+    .map(|(field, _)| *field)
+    .collect();
+    if checks.len() > 1 {
+        invalid(
+            errors,
+            path,
+            Some(checks[1]),
+            format!(
+                "`{}` and `{}` cannot be set in the same step",
+                checks[0], checks[1]
+            ),
+        );
+    }
+    if let Some(check) = &step.check_exit_code {
+        if let Err(e) = check.validate() {
+            invalid(errors, path, Some("check_exit_code"), e);
+        }
+    }
+    if let Some(signal) = &step.check_signal {
+        if let Err(e) = parse_signal(signal) {
+            invalid(errors, path, Some("check_signal"), e);
+        }
+    }
+    if step.allow_failure == Some(true)
+        && step.process_wait.is_none()
+        && step.process_kill.is_none()
+        && step.process_stop.is_none()
+    {
+        errors.push(SpecError::new(
+            path,
+            Some("allow_failure"),
+            SpecErrorKind::Warning(
+                "`allow_failure` only applies to `process_wait`, `process_kill` and `process_stop`"
+                    .to_string(),
+            ),
+        ));
+    }
+    // SIGKILL cannot be handled, so the process never gets to exit with a code
+    let kills = step.process_kill.is_some()
+        && step
+            .signal
+            .as_deref()
+            .is_none_or(|signal| parse_signal(signal) == Ok(libc::SIGKILL));
+    if kills && step.expect_success == Some(true) {
+        invalid(
+            errors,
+            path,
+            Some("expect_success"),
+            "a process killed by `SIGKILL` never succeeds, use `check_signal = \"SIGKILL\"`"
+                .to_string(),
+        );
+    }
+    if let (true, Some(check)) = (kills, &step.check_exit_code) {
+        // matched by the number of the signal instead
+        match check.matches(libc::SIGKILL) {
+            Ok(false) => invalid(
+                errors,
+                path,
+                Some("check_exit_code"),
+                "a process killed by `SIGKILL` has no exit code and is matched as 9, which `check_exit_code` rejects, use `check_signal = \"SIGKILL\"`".to_string(),
+            ),
+            Ok(true) => errors.push(SpecError::new(
+                path,
+                Some("check_exit_code"),
+                SpecErrorKind::Warning(
+                    "`check_exit_code` matching 9 for a process killed by `SIGKILL` is deprecated, use `check_signal = \"SIGKILL\"`".to_string(),
+                ),
+            )),
+            // reported above
+            Err(_) => {}
+        }
+    }
+}
+
+fn validate_shell(shell: &str, path: &str, errors: &mut Vec<SpecError>) {
+    if shell.trim() != "none" {
+        if let Err(e) = shell_argv(Some(shell), "") {
+            invalid(errors, path, Some("shell"), e);
+        }
+    }
+}
+
+fn validate_env(env: &BTreeMap<String, String>, path: &str, errors: &mut Vec<SpecError>) {
+    let name_re = Regex::new(r"^[A-Za-z_][A-Za-z0-9_]*$").unwrap();
+    for (name, value) in env {
+        if !name_re.is_match(name) {
+            invalid(
+                errors,
+                &format!("{path}.env"),
+                Some(name),
+                format!(
+                    "invalid environment variable name `{name}`, expected letters, digits and `_`"
+                ),
+            );
+        }
+        if let Err(e) = check_template(value) {
+            invalid(errors, &format!("{path}.env"), Some(name), e);
+        }
+    }
+}
+
+// Check the templates of the fields of a step that are rendered.
+fn validate_templates(step: &StepSpec, path: &str, errors: &mut Vec<SpecError>) {
+    let mut fields: Vec<(String, &String)> = [
+        ("if", &step._if),
+        ("process", &step.process),
+        ("stdin_file", &step.stdin_file),
+        ("stdin", &step.stdin),
+        ("send_variable", &step.send_variable),
+        ("recv_variable", &step.recv_variable),
+        ("file", &step.file),
+        ("stdout_file", &step.stdout_file),
+        ("stderr_file", &step.stderr_file),
+        ("exit_code", &step.exit_code),
+        ("create_entry", &step.create_entry),
+        ("update_entry", &step.update_entry),
+        ("delete_entry", &step.delete_entry),
+        ("read_entry", &step.read_entry),
+        ("read_or_wait_entry", &step.read_or_wait_entry),
+    ]
+    .into_iter()
+    .filter_map(|(field, value)| Some((field.to_string(), value.as_ref()?)))
+    .collect();
+    for (i, arg) in step.argv.iter().flatten().enumerate() {
+        fields.push((format!("argv[{i}]"), arg));
+    }
+    for (field, value) in fields {
+        if let Err(e) = check_template(value) {
+            invalid(errors, path, Some(&field), e);
+        }
+    }
+}
+
+fn validate_env_policy(role_spec: &RoleSpec, path: &str, errors: &mut Vec<SpecError>) {
+    let name_re = Regex::new(r"^[A-Za-z_][A-Za-z0-9_]*$").unwrap();
+    if let Some(env_allow) = &role_spec.env_allow {
+        for (i, pattern) in env_allow.iter().enumerate() {
+            let name = pattern.strip_suffix('*').unwrap_or(pattern);
+            // `*` alone keeps every variable
+            if !name.is_empty() && !name_re.is_match(name) {
+                invalid(
+                    errors,
+                    path,
+                    Some(&format!("env_allow[{i}]")),
+                    format!("invalid environment variable name `{pattern}`, expected letters, digits and `_`, with an optional trailing `*`"),
+                );
+            }
+        }
+        if role_spec.env_clear != Some(true) {
+            errors.push(SpecError::new(
+                path,
+                Some("env_allow"),
+                SpecErrorKind::Warning(
+                    "`env_allow` has no effect unless `env_clear = true`".to_string(),
+                ),
+            ));
+        }
+    }
+    if let Some(secrets) = &role_spec.secrets {
+        for (i, name) in secrets.iter().enumerate() {
+            if !name_re.is_match(name) {
+                invalid(
+                    errors,
+                    path,
+                    Some(&format!("secrets[{i}]")),
+                    format!("invalid environment variable name `{name}`, expected letters, digits and `_`"),
+                );
+            }
+        }
+    }
+}
+
+fn validate_limits(limits: &LimitsSpec, path: &str, errors: &mut Vec<SpecError>) {
+    let limits_path = format!("{path}.limits");
+    if let Some(cpu_time) = &limits.cpu_time {
+        if let Err(e) = parse_duration(cpu_time) {
+            invalid(errors, &limits_path, Some("cpu_time"), e);
+        }
+    }
+    for (field, size) in [
+        ("address_space", &limits.address_space),
+        ("memory_max", &limits.memory_max),
+    ] {
+        if let Some(size) = size {
+            if let Err(e) = parse_size(size) {
+                invalid(errors, &limits_path, Some(field), e);
+            }
+        }
+    }
+    if let Some(cpu_max) = &limits.cpu_max {
+        if let Err(e) = parse_cpus(cpu_max) {
+            invalid(errors, &limits_path, Some("cpu_max"), e);
+        }
+    }
+    if let Some(open_files) = limits.open_files {
+        if open_files < 1 {
+            invalid(
+                errors,
+                &limits_path,
+                Some("open_files"),
+                "`open_files` should be at least 1".to_string(),
+            );
+        }
+    }
+    if let Some(nice) = limits.nice {
+        if !(-20..=19).contains(&nice) {
+            invalid(
+                errors,
+                &limits_path,
+                Some("nice"),
+                "`nice` should be from -20 (highest priority) to 19 (lowest)".to_string(),
+            );
+        } else if nice < 0 {
+            errors.push(SpecError::new(
+                &limits_path,
+                Some("nice"),
+                SpecErrorKind::Warning(
+                    "a negative `nice` needs the operator to run with CAP_SYS_NICE".to_string(),
+                ),
+            ));
+        }
+    }
+}
+
+fn validate_sandbox_network(
+    sandbox: Option<bool>,
+    sandbox_network: Option<bool>,
+    path: &str,
+    errors: &mut Vec<SpecError>,
+) {
+    if sandbox_network == Some(true) && sandbox != Some(true) {
+        errors.push(SpecError::new(
+            path,
+            Some("sandbox_network"),
+            SpecErrorKind::Warning(
+                "`sandbox_network` has no effect unless `sandbox = true` or the operator runs with --sandbox"
+                    .to_string(),
+            ),
+        ));
+    }
+}
+
+fn validate_retry(step: &StepSpec, retry: &RetrySpec, path: &str, errors: &mut Vec<SpecError>) {
+    let retry_path = format!("{path}.retry");
+    if retry.max_attempts < 1 {
+        invalid(
+            errors,
+            &retry_path,
+            Some("max_attempts"),
+            "`max_attempts` should be at least 1".to_string(),
+        );
+    }
+    for (field, duration) in [("delay", &retry.delay), ("max_delay", &retry.max_delay)] {
+        if let Some(duration) = duration {
+            if let Err(e) = parse_duration(duration) {
+                invalid(errors, &retry_path, Some(field), e);
+            }
+        }
+    }
+    // a process started by an earlier step cannot be started again
+    let actions = set_actions(step);
+    let runs_process = step.starts_process() && step.process_wait.is_some();
+    let runs_colink = actions.iter().any(|action| !action.starts_with("process_"));
+    if let (true, Some(step_name)) = (runs_process, &step.step_name) {
+        // every attempt starts the process again, which needs the last one
+        // to be waited
+        if step.process_wait.as_ref() != Some(step_name) {
+            invalid(
+                errors,
+                path,
+                Some("process_wait"),
+                format!("`retry` starts the process again on every attempt, so `process_wait` should wait for the process of this step, `{step_name}`"),
+            );
+        }
+    } else if !runs_process && !runs_colink {
+        invalid(
+            errors,
+            path,
+            Some("retry"),
+            "`retry` needs a step that starts and waits its own process (`process` or `argv` with `process_wait`) or a CoLink action".to_string(),
+        );
+    }
+    if retry.exit_codes.is_some() && !retry.retries_on(RetryOn::ExitCode) {
+        errors.push(SpecError::new(
+            &retry_path,
+            Some("exit_codes"),
+            SpecErrorKind::Warning(
+                "`exit_codes` has no effect unless `on` includes \"exit_code\"".to_string(),
+            ),
+        ));
+    }
+    if runs_process
+        && retry.retries_on(RetryOn::ExitCode)
+        && step.check_exit_code.is_none()
+        && step.check_signal.is_none()
+        && step.expect_success != Some(true)
+    {
+        errors.push(SpecError::new(
+            path,
+            Some("retry"),
+            SpecErrorKind::Warning("exit codes are only checked with `check_exit_code`, `check_signal` or `expect_success`, the process will not be retried on its exit code".to_string()),
+        ));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::spec_parser::parse_spec_from_toml;
+
+    // The errors and warnings of protocol `t` with the `roles` tables.
+    fn errors(roles: &str) -> Vec<String> {
+        let toml_str = format!(
+            "[package]\nuse_playbook = true\n[t]\nname = \"t\"\nworkdir = \"/tmp\"\n{roles}"
+        );
+        let package_spec = parse_spec_from_toml(&toml_str).unwrap_or_else(|e| panic!("{e}"));
+        validate_package(&package_spec)
+            .iter()
+            .map(|error| error.to_string())
+            .collect()
+    }
+
+    #[test]
+    fn valid_playbook() {
+        let errors = errors(
+            r#"
+[t.roles.a.playbook]
+grace_period = "10s"
+env = { MODE = "{{user_id}}" }
+[[t.roles.a.playbook.steps]]
+step_name = "server"
+process = "python3 server.py"
+[[t.roles.a.playbook.steps]]
+step_name = "prepare"
+argv = ["cp", "a", "b-{{task_id[..8]}}"]
+process_wait = "prepare"
+timeout = "1m"
+check_exit_code = 0
+[[t.roles.a.playbook.steps]]
+if = "test -f b"
+process_stop = "server"
+[[t.roles.a.playbook.steps]]
+process_kill = "server"
+check_signal = "SIGKILL"
+"#,
+        );
+        assert!(errors.is_empty(), "{errors:?}");
+    }
+
+    #[test]
+    fn conflicting_actions() {
+        let errors = errors(
+            r#"
+[[t.roles.a.playbook.steps]]
+create_entry = "e"
+delete_entry = "e"
+file = "f"
+[[t.roles.a.playbook.steps]]
+step_name = "p"
+process = "true"
+read_entry = "e"
+file = "f"
+[[t.roles.a.playbook.steps]]
+step_name = "q"
+process = "true"
+argv = ["true"]
+process_wait = "q"
+[[t.roles.a.playbook.steps]]
+file = "f"
+"#,
+        );
+        assert_eq!(
+            errors,
+            [
+                "t.roles.a.playbook.steps[0].delete_entry: `create_entry` and `delete_entry` cannot be set in the same step",
+                "t.roles.a.playbook.steps[1].process: `process` cannot be set together with `read_entry`",
+                "t.roles.a.playbook.steps[2].argv: `process` and `argv` cannot be set in the same step",
+                "t.roles.a.playbook.steps[3]: step does not define any action",
+            ]
+        );
+    }
+
+    #[test]
+    fn process_lifecycle() {
+        let errors = errors(
+            r#"
+[[t.roles.a.playbook.steps]]
+process = "true"
+[[t.roles.a.playbook.steps]]
+step_name = "__p"
+process = "true"
+process_wait = "__p"
+[[t.roles.a.playbook.steps]]
+process_kill = "never_started"
+[[t.roles.a.playbook.steps]]
+step_name = "p"
+process = "true"
+process_wait = "p"
+[[t.roles.a.playbook.steps]]
+process_wait = "p"
+"#,
+        );
+        assert_eq!(
+            errors,
+            [
+                "t.roles.a.playbook.steps[0].process: `process` need `step_name`",
+                "t.roles.a.playbook.steps[1].step_name: `step_name` cannot start with `__`",
+                "t.roles.a.playbook.steps[2].process_kill: process `never_started` is not started by an earlier step",
+                "t.roles.a.playbook.steps[4].process_wait: process `p` is not started by an earlier step",
+            ]
+        );
+    }
+
+    #[test]
+    fn step_name_still_running() {
+        let errors = errors(
+            r#"
+[[t.roles.a.playbook.steps]]
+step_name = "s"
+process = "sleep 41"
+[[t.roles.a.playbook.steps]]
+if = "false"
+process_kill = "s"
+[[t.roles.a.playbook.steps]]
+step_name = "s"
+process = "sleep 42"
+[[t.roles.a.playbook.steps]]
+process_kill = "s"
+[[t.roles.a.playbook.steps]]
+step_name = "s"
+process = "sleep 43"
+process_wait = "s"
+"#,
+        );
+        assert_eq!(
+            errors,
+            ["t.roles.a.playbook.steps[2].step_name: process `s` started by steps[0] may still be running, it should be waited, killed or stopped before its `step_name` is used again"]
+        );
+    }
+
+    #[test]
+    fn conditional_start() {
+        let errors = errors(
+            r#"
+[[t.roles.a.playbook.steps]]
+if = "false"
+step_name = "p"
+process = "sleep 1"
+[[t.roles.a.playbook.steps]]
+if = "false"
+process_wait = "p"
+[[t.roles.a.playbook.steps]]
+if = "true"
+step_name = "q"
+process = "sleep 1"
+[[t.roles.a.playbook.steps]]
+if = "test -f x"
+process_stop = "q"
+[[t.roles.a.playbook.steps]]
+process_wait = "q"
+"#,
+        );
+        assert_eq!(
+            errors,
+            [
+                "warning: t.roles.a.playbook.steps[3].process_stop: process `q` is only started if the `if` of steps[2] succeeds, this step fails when it does not, unless it has the same `if`",
+                "t.roles.a.playbook.steps[4].process_wait: process `q` is only started if the `if` of steps[2] succeeds, this step fails when it does not, unless it has the same `if`",
+            ]
+        );
+    }
+
+    #[test]
+    fn transfers_and_entries() {
+        let errors = errors(
+            r#"
+[[t.roles.a.playbook.steps]]
+send_variable = "x"
+[[t.roles.a.playbook.steps]]
+recv_variable = "y"
+from_role = "c"
+index = -1
+[[t.roles.a.playbook.steps]]
+read_or_wait_entry = "e"
+"#,
+        );
+        assert_eq!(
+            errors,
+            [
+                "t.roles.a.playbook.steps[0].send_variable: `send_variable` need `file`",
+                "t.roles.a.playbook.steps[0].send_variable: `send_variable` need `to_role`",
+                "t.roles.a.playbook.steps[1].from_role: role `c` is not defined in protocol `t`",
+                "t.roles.a.playbook.steps[1].index: `index` cannot be negative",
+                "t.roles.a.playbook.steps[2].read_or_wait_entry: `read_or_wait_entry` need `file`",
+            ]
+        );
+    }
+
+    #[test]
+    fn fields_that_do_not_apply() {
+        let errors = errors(
+            r#"
+[[t.roles.a.playbook.steps]]
+delete_entry = "e"
+timeout = "1s"
+signal = "SIGINT"
+grace_period = "1s"
+allow_failure = true
+env = { A = "1" }
+stdin = "input"
+"#,
+        );
+        assert_eq!(
+            errors,
+            [
+                "warning: t.roles.a.playbook.steps[0].stdin: `stdin` only applies to `process` and `argv`",
+                "warning: t.roles.a.playbook.steps[0].env: `env` only applies to `process`, `argv` and `if`",
+                "warning: t.roles.a.playbook.steps[0].timeout: `timeout` only applies to `process_wait`, `recv_variable` and `read_or_wait_entry`",
+                "warning: t.roles.a.playbook.steps[0].signal: `signal` only applies to `process_kill` and `process_stop`",
+                "warning: t.roles.a.playbook.steps[0].allow_failure: `allow_failure` only applies to `process_wait`, `process_kill` and `process_stop`",
+                "warning: t.roles.a.playbook.steps[0].grace_period: `grace_period` only applies to `process_stop` and `process_kill`",
+            ]
+        );
+    }
+
+    #[test]
+    fn invalid_values() {
+        let errors = errors(
+            r#"
+[t.roles.a.playbook]
+workdir = "/tmp/{{taskid}}"
+grace_period = "soon"
+env = { "NOT-A-NAME" = "1" }
+[[t.roles.a.playbook.steps]]
+step_name = "p"
+process = "echo {{user_id[5..2]}}"
+process_wait = "p"
+timeout = "1x"
+[[t.roles.a.playbook.steps]]
+step_name = "q"
+process = "sleep 1"
+process_kill = "q"
+signal = "SIGNOPE"
+check_exit_code = "2-"
+"#,
+        );
+        assert_eq!(
+            errors,
+            [
+                "t.roles.a.playbook.workdir: unknown template variable `{{taskid}}`, expected `task_id` or `user_id`",
+                "t.roles.a.playbook.grace_period: invalid duration `soon`, expected e.g. `500ms`, `30s`, `2h` or `1h30m`",
+                "t.roles.a.playbook.env.NOT-A-NAME: invalid environment variable name `NOT-A-NAME`, expected letters, digits and `_`",
+                "t.roles.a.playbook.steps[0].process: the slice of `{{user_id[5..2]}}` ends before it starts",
+                "t.roles.a.playbook.steps[0].timeout: invalid duration `1x`, expected e.g. `500ms`, `30s`, `2h` or `1h30m`",
+                "t.roles.a.playbook.steps[1].signal: unknown signal `SIGNOPE`, expected a name like `SIGTERM`, `SIGINT` or `SIGKILL`",
+                "t.roles.a.playbook.steps[1].check_exit_code: invalid exit code pattern `2-`, expected e.g. `3`, `\"2-5\"` or `\"!0\"`",
+            ]
+        );
+    }
+
+    #[test]
+    fn retry() {
+        let errors = errors(
+            r#"
+[[t.roles.a.playbook.steps]]
+step_name = "other"
+process = "true"
+process_wait = "other"
+[[t.roles.a.playbook.steps]]
+step_name = "s"
+process = "sleep 30"
+process_wait = "other"
+expect_success = true
+retry = { max_attempts = 3 }
+[[t.roles.a.playbook.steps]]
+process_wait = "s"
+retry = { max_attempts = 0, delay = "1x" }
+[[t.roles.a.playbook.steps]]
+step_name = "p"
+process = "true"
+process_wait = "p"
+retry = { max_attempts = 2, on = ["colink"], exit_codes = [75] }
+"#,
+        );
+        assert_eq!(
+            errors,
+            [
+                "t.roles.a.playbook.steps[1].process_wait: process `other` is not started by an earlier step",
+                "t.roles.a.playbook.steps[1].process_wait: `retry` starts the process again on every attempt, so `process_wait` should wait for the process of this step, `s`",
+                "t.roles.a.playbook.steps[2].retry.max_attempts: `max_attempts` should be at least 1",
+                "t.roles.a.playbook.steps[2].retry.delay: invalid duration `1x`, expected e.g. `500ms`, `30s`, `2h` or `1h30m`",
+                "t.roles.a.playbook.steps[2].retry: `retry` needs a step that starts and waits its own process (`process` or `argv` with `process_wait`) or a CoLink action",
+                "warning: t.roles.a.playbook.steps[3].retry.exit_codes: `exit_codes` has no effect unless `on` includes \"exit_code\"",
+            ]
+        );
+    }
+
+    #[test]
+    fn outcome_checks() {
+        let errors = errors(
+            r#"
+[[t.roles.a.playbook.steps]]
+step_name = "p"
+process = "sleep 30"
+process_kill = "p"
+check_exit_code = 9
+[[t.roles.a.playbook.steps]]
+step_name = "q"
+process = "sleep 30"
+process_kill = "q"
+check_exit_code = 0
+[[t.roles.a.playbook.steps]]
+step_name = "r"
+process = "sleep 30"
+process_kill = "r"
+signal = "SIGKILL"
+expect_success = true
+[[t.roles.a.playbook.steps]]
+step_name = "s"
+process = "sleep 30"
+process_kill = "s"
+signal = "SIGTERM"
+check_exit_code = 0
+check_signal = "SIGTERM"
+"#,
+        );
+        assert_eq!(
+            errors,
+            [
+                "warning: t.roles.a.playbook.steps[0].check_exit_code: `check_exit_code` matching 9 for a process killed by `SIGKILL` is deprecated, use `check_signal = \"SIGKILL\"`",
+                "t.roles.a.playbook.steps[1].check_exit_code: a process killed by `SIGKILL` has no exit code and is matched as 9, which `check_exit_code` rejects, use `check_signal = \"SIGKILL\"`",
+                "t.roles.a.playbook.steps[2].expect_success: a process killed by `SIGKILL` never succeeds, use `check_signal = \"SIGKILL\"`",
+                "t.roles.a.playbook.steps[3].check_signal: `check_exit_code` and `check_signal` cannot be set in the same step",
+            ]
+        );
+    }
+}